@@ -1,7 +1,9 @@
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
-use store::config::StoreConfig;
+use store::config::{StoreConfig, SyncMode};
 use store::BitCask;
 use tempfile::{tempdir, TempDir};
 
@@ -12,6 +14,7 @@ fn default_bitcask() -> (BitCask, TempDir) {
     let cfg = StoreConfig {
         log_dir: dir.path().to_path_buf(),
         max_log_file_size: 10_000_000,
+        ..Default::default()
     };
     // Return to ensure tempdir does not go out of scope.
     (BitCask::new(Arc::new(cfg)).unwrap(), dir)
@@ -22,29 +25,216 @@ fn benchmark_get(c: &mut Criterion) {
     let mut group = c.benchmark_group("get");
     for size in SIZES.iter() {
         group.throughput(Throughput::Bytes(*size as u64));
-        let val = std::iter::repeat("@").take(*size).collect::<String>();
-        bitcask.set("foo", &val).unwrap();
+        let val = "@".repeat(*size);
+        bitcask.set(b"foo", val.as_bytes()).unwrap();
         group.bench_with_input(BenchmarkId::from_parameter(size), size, |b, _| {
-            b.iter(|| bitcask.get(black_box("foo")));
+            b.iter(|| bitcask.get(black_box(b"foo")));
         });
     }
     group.finish();
 }
 
+/// Reads spread over a growing number of threads, which shouldn't get in each other's way.
+fn benchmark_get_concurrent(c: &mut Criterion) {
+    const READS_PER_THREAD: usize = 1000;
+    let (bitcask, _dir) = default_bitcask();
+    let keys: Vec<_> = (0..1000).map(|i| format!("key{}", i)).collect();
+    for key in &keys {
+        bitcask.set(key.as_bytes(), &[b'@'; 1024]).unwrap();
+    }
+    let mut group = c.benchmark_group("get_concurrent");
+    for threads in [1, 2, 4, 8] {
+        group.throughput(Throughput::Elements((threads * READS_PER_THREAD) as u64));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(threads),
+            &threads,
+            |b, &threads| {
+                b.iter(|| {
+                    std::thread::scope(|s| {
+                        for t in 0..threads {
+                            let (bitcask, keys) = (&bitcask, &keys);
+                            s.spawn(move || {
+                                for i in 0..READS_PER_THREAD {
+                                    let key = &keys[(t * 7919 + i) % keys.len()];
+                                    black_box(bitcask.get(key.as_bytes()).unwrap());
+                                }
+                            });
+                        }
+                    });
+                });
+            },
+        );
+    }
+    group.finish();
+}
+
 fn benchmark_set(c: &mut Criterion) {
     let (bitcask, _dir) = default_bitcask();
     let mut group = c.benchmark_group("set");
     for size in SIZES.iter() {
         group.throughput(Throughput::Bytes(*size as u64));
-        let val = std::iter::repeat("@").take(*size).collect::<String>();
+        let val = "@".repeat(*size);
         group.bench_with_input(BenchmarkId::from_parameter(size), size, |b, _| {
             // TODO insane to set 2.2M times (would merge in normal circumstances)
             // must be a different way to bench this.
-            b.iter(|| bitcask.set(black_box("foo"), black_box(&val)));
+            b.iter(|| bitcask.set(black_box(b"foo"), black_box(val.as_bytes())));
         });
     }
     group.finish();
 }
 
-criterion_group!(benches, benchmark_get, benchmark_set);
+/// Durable writes spread over a growing number of threads, which group commit lets share
+/// their syncs.
+fn benchmark_set_concurrent(c: &mut Criterion) {
+    const WRITES_PER_THREAD: usize = 20;
+    let dir = tempdir().unwrap();
+    let cfg = StoreConfig {
+        log_dir: dir.path().to_path_buf(),
+        max_log_file_size: 10_000_000,
+        sync_mode: SyncMode::Always,
+        ..Default::default()
+    };
+    let bitcask = BitCask::new(Arc::new(cfg)).unwrap();
+    let val = [b'@'; 1024];
+    let mut group = c.benchmark_group("set_concurrent");
+    for threads in [1, 4, 16] {
+        group.throughput(Throughput::Elements((threads * WRITES_PER_THREAD) as u64));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(threads),
+            &threads,
+            |b, &threads| {
+                b.iter(|| {
+                    std::thread::scope(|s| {
+                        for t in 0..threads {
+                            let bitcask = &bitcask;
+                            s.spawn(move || {
+                                for i in 0..WRITES_PER_THREAD {
+                                    let key = format!("key{}-{}", t, i);
+                                    bitcask.set(key.as_bytes(), black_box(&val)).unwrap();
+                                }
+                            });
+                        }
+                    });
+                });
+            },
+        );
+    }
+    group.finish();
+}
+
+/// Latencies of individual operations, for the percentiles criterion doesn't report.
+#[derive(Default)]
+struct Latencies(Vec<Duration>);
+
+impl Latencies {
+    /// Run `op` `iters` times, recording how long each run took if `record` says to, and
+    /// return the total for criterion.
+    fn time(&mut self, iters: u64, record: impl Fn() -> bool, mut op: impl FnMut()) -> Duration {
+        let mut total = Duration::ZERO;
+        for _ in 0..iters {
+            let recording = record();
+            let start = Instant::now();
+            op();
+            let took = start.elapsed();
+            total += took;
+            if recording {
+                self.0.push(took);
+            }
+        }
+        total
+    }
+
+    fn report(&mut self, name: &str) {
+        if self.0.is_empty() {
+            println!("{}: no samples", name);
+            return;
+        }
+        self.0.sort();
+        let percentile = |p: usize| self.0[(self.0.len() - 1) * p / 100];
+        println!(
+            "{}: p50 {:?}, p99 {:?}, max {:?} over {} writes",
+            name,
+            percentile(50),
+            percentile(99),
+            self.0.last().unwrap(),
+            self.0.len()
+        );
+    }
+}
+
+/// Writes with merges of a sizable store running back to back, next to the same writes with
+/// nothing else going on. The merge shouldn't hold them up for long at any point, so besides
+/// the mean, the p50, p99 and max latencies are printed, counting only writes made while a
+/// merge was underway. The store holds `BITCASK_BENCH_MERGE_KEYS` 1 KiB values, enough by
+/// default for each merge to take a while.
+fn benchmark_set_during_merge(c: &mut Criterion) {
+    let keys: usize = std::env::var("BITCASK_BENCH_MERGE_KEYS")
+        .ok()
+        .and_then(|keys| keys.parse().ok())
+        .unwrap_or(200_000);
+    let val = [b'@'; 1024];
+    let populated_bitcask = || {
+        let dir = tempdir().unwrap();
+        let cfg = StoreConfig {
+            log_dir: dir.path().to_path_buf(),
+            max_log_file_size: 4_000_000,
+            ..Default::default()
+        };
+        let bitcask = BitCask::new(Arc::new(cfg)).unwrap();
+        for i in 0..keys {
+            bitcask.set(format!("key{}", i).as_bytes(), &val).unwrap();
+        }
+        (bitcask, dir)
+    };
+    // Overwrite the existing keys in turn, so the store stays the same size throughout.
+    let overwrite = |bitcask: &BitCask, i: &mut usize| {
+        *i = (*i + 1) % keys;
+        let key = format!("key{}", i);
+        bitcask.set(key.as_bytes(), black_box(&val)).unwrap();
+    };
+    let mut group = c.benchmark_group("set_during_merge");
+
+    let (bitcask, _dir) = populated_bitcask();
+    let (mut i, mut latencies) = (0, Latencies::default());
+    group.bench_function("idle", |b| {
+        b.iter_custom(|iters| latencies.time(iters, || true, || overwrite(&bitcask, &mut i)))
+    });
+    latencies.report("set_during_merge/idle");
+
+    let (bitcask, _dir) = populated_bitcask();
+    let (merging, in_merge) = (AtomicBool::new(true), AtomicBool::new(false));
+    let merges = std::thread::scope(|s| {
+        let merger = s.spawn(|| {
+            let mut merges = 0;
+            while merging.load(Ordering::Relaxed) {
+                in_merge.store(true, Ordering::Relaxed);
+                bitcask.merge().unwrap();
+                in_merge.store(false, Ordering::Relaxed);
+                merges += 1;
+            }
+            merges
+        });
+        let (mut i, mut latencies) = (0, Latencies::default());
+        group.bench_function("merging", |b| {
+            b.iter_custom(|iters| {
+                let record = || in_merge.load(Ordering::Relaxed);
+                latencies.time(iters, record, || overwrite(&bitcask, &mut i))
+            })
+        });
+        latencies.report("set_during_merge/merging");
+        merging.store(false, Ordering::Relaxed);
+        merger.join().unwrap()
+    });
+    println!("set_during_merge/merging: {} merges completed", merges);
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    benchmark_get,
+    benchmark_get_concurrent,
+    benchmark_set,
+    benchmark_set_concurrent,
+    benchmark_set_during_merge
+);
 criterion_main!(benches);
@@ -1,9 +1,9 @@
 use std::sync::Arc;
 
-use bytes::BytesMut;
+use bytes::{Bytes, BytesMut};
 use log::{debug, info};
 use simple_logger::SimpleLogger;
-use store::{get_store_config, BitCask, Result};
+use store::{get_store_config, BitCask};
 use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::{mpsc, oneshot};
@@ -14,7 +14,9 @@ use crate::config::get_server_config;
 mod command;
 mod config;
 
-pub type BitCaskTx = mpsc::Sender<(Command, oneshot::Sender<Option<Vec<u8>>>)>;
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+pub type BitCaskTx = mpsc::Sender<(Command, oneshot::Sender<Option<Bytes>>)>;
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -38,9 +40,10 @@ async fn main() -> Result<()> {
                 tokio::spawn(async move {
                     let res = server_rx.await.unwrap();
                     if let Some(res) = res {
-                        debug!("sending response: {}", std::str::from_utf8(&res).unwrap());
-                        stream.write_all(&res).await.unwrap();
-                        stream.flush().await.unwrap();
+                        debug!("sending response: {}", String::from_utf8_lossy(&res));
+                        // Nothing's buffered, so skip the copy into the buffer and write the
+                        // value, which may be straight out of a log file's mmap, directly.
+                        stream.get_mut().write_all(&res).await.unwrap();
                     }
                 });
             }
@@ -53,7 +56,7 @@ async fn main() -> Result<()> {
 }
 
 /// Put data sent from connection through command parser.
-async fn parse_command<'cfg>(stream: &mut BufWriter<TcpStream>) -> Result<Command> {
+async fn parse_command(stream: &mut BufWriter<TcpStream>) -> Result<Command> {
     let mut buf = BytesMut::with_capacity(4 * 1024);
     stream.read_buf(&mut buf).await?;
     let input = std::str::from_utf8(&buf)?;
@@ -61,7 +64,7 @@ async fn parse_command<'cfg>(stream: &mut BufWriter<TcpStream>) -> Result<Comman
 }
 
 fn bitcask_loop(bitcask: BitCask) -> BitCaskTx {
-    let (tx, mut rx) = mpsc::channel::<(Command, oneshot::Sender<Option<Vec<u8>>>)>(32);
+    let (tx, mut rx) = mpsc::channel::<(Command, oneshot::Sender<Option<Bytes>>)>(32);
     let bitcask = Arc::new(bitcask);
 
     tokio::spawn(async move {
@@ -78,7 +81,7 @@ fn bitcask_loop(bitcask: BitCask) -> BitCaskTx {
                 Command::Get(key) => {
                     let bitcask = bitcask.clone();
                     tokio::spawn(async move {
-                        let val = bitcask.get(&key).unwrap();
+                        let val = bitcask.get_bytes(&key).unwrap();
                         resp_tx.send(Some(val)).unwrap();
                     });
                 }
@@ -90,7 +93,9 @@ fn bitcask_loop(bitcask: BitCask) -> BitCaskTx {
                     let bitcask = bitcask.clone();
                     tokio::spawn(async move {
                         bitcask.merge().unwrap();
-                        resp_tx.send(Some(b"all done!".to_vec())).unwrap();
+                        resp_tx
+                            .send(Some(Bytes::from_static(b"all done!")))
+                            .unwrap();
                     });
                 }
             };
@@ -0,0 +1,57 @@
+use crate::log::{flags, LogEntry};
+
+/// A group of sets and deletes that `BitCask::write_batch` applies all at once.
+///
+/// Either every operation in the batch becomes visible, or none of them do, including across
+/// a crash in the middle of writing it.
+#[derive(Debug, Default)]
+pub struct WriteBatch {
+    /// Keys along with their new values, or `None` to delete them.
+    ops: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(&mut self, key: &[u8], val: &[u8]) -> &mut Self {
+        self.ops.push((key.to_vec(), Some(val.to_vec())));
+        self
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> &mut Self {
+        self.ops.push((key.to_vec(), None));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Keys along with their new values, or `None` where they get deleted.
+    pub(crate) fn ops(&self) -> impl Iterator<Item = (&[u8], Option<&[u8]>)> {
+        self.ops
+            .iter()
+            .map(|(key, val)| (key.as_slice(), val.as_deref()))
+    }
+
+    /// Turn the operations into log entries sharing a single timestamp.
+    pub(crate) fn into_entries(self, ts: u128) -> Vec<LogEntry> {
+        self.ops
+            .into_iter()
+            .map(|(key, val)| {
+                let mut entry = match val {
+                    Some(val) => LogEntry::from_set(&key, &val, ts),
+                    None => LogEntry::tombstone(&key, ts),
+                };
+                entry.flags |= flags::IN_BATCH;
+                entry
+            })
+            .collect()
+    }
+}
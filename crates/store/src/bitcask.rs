@@ -1,39 +1,25 @@
-use std::fmt;
+use std::collections::BTreeMap;
+use std::ops::RangeBounds;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
 
-use log::info;
+use bytes::Bytes;
+use log::{info, warn};
 
-use crate::config::StoreConfig;
-use crate::keydir::KeyDir;
-use crate::log::files::FileManager;
-use crate::log::read::HintReader;
-use crate::log::LogEntry;
-use crate::merge::merge;
-
-// TODO should this one be a &str?
-// TODO reexport under `store::errors::...`?
-// TODO should probably be in the KeyDir file.
-#[derive(Debug)]
-pub struct KeyMiss;
-
-impl fmt::Display for KeyMiss {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Key miss")
-    }
-}
-
-impl std::error::Error for KeyMiss {}
-
-#[derive(Debug)]
-pub struct MergeUnderway;
-
-impl fmt::Display for MergeUnderway {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Merge already underway!")
-    }
-}
-
-impl std::error::Error for MergeUnderway {}
+use crate::batch::WriteBatch;
+use crate::commit::{GroupCommit, Write};
+use crate::config::{CorruptionPolicy, StoreConfig, SyncMode};
+use crate::error::Error;
+use crate::iter::{Iter, Keys};
+use crate::keydir::{Item, KeyDir, Version};
+use crate::lock::DirLock;
+use crate::log::files::{FileManager, FileStats, LogFiles, ReadEntry};
+use crate::log::read::{Committed, HintReader};
+use crate::log::{now, MAX_EXPIRY};
+use crate::merge::{MergePolicy, Merger};
+use crate::scheduler::MergeScheduler;
+use crate::sync::Syncer;
 
 pub type SharedKeyDir = Arc<RwLock<KeyDir>>;
 
@@ -41,7 +27,15 @@ pub struct BitCask {
     pub config: Arc<StoreConfig>,
     keydir: SharedKeyDir,
     file_manager: Arc<Mutex<FileManager>>,
-    merge_mutex: Arc<Mutex<()>>,
+    /// For reading values without locking the `FileManager`.
+    log_files: Arc<LogFiles>,
+    merger: Merger,
+    group_commit: GroupCommit,
+    _merge_scheduler: Option<MergeScheduler>,
+    _syncer: Option<Syncer>,
+    read_only: bool,
+    // Last, so it's only released once everything else has shut down.
+    _lock: Option<DirLock>,
 }
 
 impl BitCask {
@@ -52,100 +46,478 @@ impl BitCask {
             info!("Directory not found! Creating...");
             std::fs::create_dir_all(&config.log_dir)?;
         }
+        config.compression.check_available()?;
+        config.check_merge_policy()?;
+        let lock = DirLock::acquire(&config.log_dir)?;
 
-        let mut file_manager = FileManager::new(config.clone());
-        file_manager.initialize_from_log_dir()?;
-        let keydir = Self::initialize_keydir(&mut file_manager);
+        let file_manager = FileManager::new(config.clone())?;
+        file_manager.truncate_torn_tails()?;
+        file_manager.upgrade_files()?;
+        let (file_manager, keydir) = Self::load(file_manager)?;
+
+        let log_files = file_manager.log_files();
+        let file_manager = Arc::new(Mutex::new(file_manager));
+        let syncer = match config.sync_mode {
+            SyncMode::Interval => Some(Syncer::spawn(
+                file_manager.clone(),
+                Duration::from_millis(config.sync_interval_ms),
+            )),
+            SyncMode::Always | SyncMode::Os => None,
+        };
+        let keydir = Arc::new(RwLock::new(keydir));
+        let merger = Merger {
+            config: config.clone(),
+            keydir: keydir.clone(),
+            file_manager: file_manager.clone(),
+            merge_mutex: Arc::new(Mutex::new(())),
+        };
+        let merge_scheduler = config.auto_merge.then(|| {
+            MergeScheduler::spawn(
+                merger.clone(),
+                Duration::from_millis(config.merge_check_interval_ms),
+            )
+        });
 
         Ok(Self {
             config,
-            keydir: Arc::new(RwLock::new(keydir)),
-            file_manager: Arc::new(Mutex::new(file_manager)),
+            keydir,
+            file_manager,
+            log_files,
+            merger,
+            group_commit: GroupCommit::default(),
+            _merge_scheduler: merge_scheduler,
+            _syncer: syncer,
+            read_only: false,
+            _lock: Some(lock),
+        })
+    }
+
+    /// Open the store in `config.log_dir` for reading alongside the process writing to it,
+    /// if any. Writes and merges fail with `Error::ReadOnly`, and nothing in the directory is
+    /// ever modified. Call `refresh` to pick up whatever the writer has done since.
+    pub fn open_read_only(config: Arc<StoreConfig>) -> crate::Result<Self> {
+        info!("Initializing read-only BitCask in {:?}", config.log_dir);
+        let (file_manager, keydir) = Self::load(FileManager::read_only(config.clone())?)?;
+        let keydir = Arc::new(RwLock::new(keydir));
+        let log_files = file_manager.log_files();
+        let file_manager = Arc::new(Mutex::new(file_manager));
+        let merger = Merger {
+            config: config.clone(),
+            keydir: keydir.clone(),
+            file_manager: file_manager.clone(),
             merge_mutex: Arc::new(Mutex::new(())),
+        };
+        Ok(Self {
+            config,
+            keydir,
+            log_files,
+            file_manager,
+            merger,
+            group_commit: GroupCommit::default(),
+            _merge_scheduler: None,
+            _syncer: None,
+            read_only: true,
+            _lock: None,
         })
     }
 
+    /// Reload a read-only store from its directory, to see the files written and merged
+    /// since it was opened. A writable store is always up to date, so this does nothing.
+    pub fn refresh(&self) -> crate::Result<()> {
+        if !self.read_only {
+            return Ok(());
+        }
+        let (mut new_file_manager, new_keydir) =
+            Self::load(FileManager::read_only(self.config.clone())?)?;
+        let mut file_manager = self.file_manager.lock().unwrap();
+        // Swap the files along with the `KeyDir`, so a reader finding its file gone also
+        // finds the key moved elsewhere.
+        let mut keydir = self.keydir.write().unwrap();
+        new_file_manager.publish_to(&self.log_files);
+        *file_manager = new_file_manager;
+        *keydir = new_keydir;
+        Ok(())
+    }
+
+    /// Open the files in the log directory, and build the `KeyDir` from them.
+    fn load(mut file_manager: FileManager) -> crate::Result<(FileManager, KeyDir)> {
+        file_manager.initialize_from_log_dir()?;
+        let keydir = Self::initialize_keydir(&mut file_manager)?;
+        Ok((file_manager, keydir))
+    }
+
     /// Construct `KeyDir` reflecting existing data in log- and hintfiles in directory.
-    pub fn initialize_keydir(file_manager: &mut FileManager) -> KeyDir {
-        file_manager
-            .iter_mut()
-            .flat_map(|handle| {
-                match handle.get_hint_file(false).as_mut() {
-                    Some(hint_file) => {
-                        let hint_reader = HintReader::new(hint_file);
-                        hint_reader.flatten().collect::<Vec<_>>()
-                    }
-                    None => {
-                        // TODO probably should log errors instead of just flattening!
-                        handle
-                            .flatten()
-                            .map(|ri| ri.into_key_item_tuple())
-                            .collect::<Vec<_>>()
+    /// Corrupt entries are dealt with according to the configured `CorruptionPolicy`. Hint
+    /// files only stand in for their log files, so a bad one is passed over for its log file.
+    pub fn initialize_keydir(file_manager: &mut FileManager) -> crate::Result<KeyDir> {
+        let policy = file_manager.config().corruption_policy;
+        let keyring = file_manager.keyring();
+        // The latest write of each key, deletes included. Files don't sort by the age of
+        // their contents once merged, so the timestamps decide.
+        let mut latest = BTreeMap::new();
+        let mut quarantined = Vec::new();
+        // How many entries each file holds, for its `FileStats`.
+        let mut entries = BTreeMap::new();
+        for handle in file_manager.iter_mut() {
+            let count = entries.entry(handle.path.clone()).or_insert(0);
+            if let Some(hint_file) = handle.get_hint_file(false)?.as_mut() {
+                match HintReader::new(hint_file, handle.len()?).collect::<crate::Result<Vec<_>>>() {
+                    Ok(hinted) => {
+                        for hint in hinted {
+                            let deleted = hint.is_tombstone()
+                                || hint.may_be_legacy_tombstone()
+                                    && handle
+                                        .read_entry(hint.item.val_pos as usize)?
+                                        .is_tombstone();
+                            let key = keyring.decrypt_key(
+                                hint.key,
+                                hint.item.ts,
+                                hint.flags,
+                                &handle.path,
+                                hint.item.val_pos,
+                            )?;
+                            keep_latest(&mut latest, key, hint.item, deleted);
+                            *count += 1;
+                        }
+                        continue;
                     }
+                    // Such as one a merge was cut short in the middle of writing.
+                    Err(e) => warn!("Falling back on {:?} over its hints: {}", handle.path, e),
+                }
+            }
+
+            // Hold entries back until the whole file is known to be fit for use.
+            let mut read_items = Vec::new();
+            for read in Committed::new(&mut *handle) {
+                match read {
+                    Ok(read) => read_items.push(read),
+                    Err(e) => match policy {
+                        CorruptionPolicy::Fail => return Err(e),
+                        CorruptionPolicy::Skip => warn!("Skipping corrupt entry: {}", e),
+                        CorruptionPolicy::Quarantine => {
+                            warn!("Found corrupt entry: {}", e);
+                            quarantined.push(handle.path.clone());
+                            break;
+                        }
+                    },
                 }
-            })
-            .fold(KeyDir::default(), |mut keydir, (key, item)| {
-                keydir.set(key, item);
-                keydir
-            })
+            }
+            if quarantined.last() != Some(&handle.path) {
+                for read in read_items {
+                    let deleted = read.entry.is_tombstone();
+                    let flags = read.entry.flags;
+                    let (key, item) = read.into_key_item_tuple();
+                    let key = keyring.decrypt_key(key, item.ts, flags, &item.path, item.val_pos)?;
+                    keep_latest(&mut latest, key, item, deleted);
+                    *count += 1;
+                }
+            }
+        }
+        for path in quarantined {
+            file_manager.quarantine(&path)?;
+        }
+        if let Some(ts) = latest.values().map(|(item, _)| item.ts).max() {
+            file_manager.observe_ts(ts);
+        }
+        let data = latest
+            .into_iter()
+            .filter_map(|(key, (item, deleted))| (!deleted).then_some((key, item)))
+            .collect();
+        let keydir = KeyDir { data };
+        file_manager.recount(&keydir, &entries)?;
+        Ok(keydir)
     }
 
-    pub fn set(&self, key: &[u8], val: &[u8]) -> crate::Result<()> {
-        let entry = LogEntry::from_set(key, val)?;
-        let key = entry.key.clone();
-        let mut file_manager = self.file_manager.lock().unwrap();
-        let item = file_manager.set(&entry)?;
-        self.keydir.write().unwrap().set(key, item);
+    pub fn set(&self, key: &[u8], val: &[u8]) -> crate::Result<Version> {
+        self.set_if(key, val, None, |_| Ok(()))
+    }
+
+    /// Set `key` such that it reads as missing once `ttl` has passed. TTLs reaching past the
+    /// latest expiry files can hold, in the year 4253, are cut short to it.
+    pub fn set_with_ttl(&self, key: &[u8], val: &[u8], ttl: Duration) -> crate::Result<Version> {
+        // Clamped here rather than only when written, so the `KeyDir` agrees with the files.
+        let expiry = now()?
+            .checked_add(ttl.as_micros())
+            .map_or(MAX_EXPIRY, |expiry| std::cmp::min(expiry, MAX_EXPIRY));
+        self.set_if(key, val, Some(expiry), |_| Ok(()))
+    }
+
+    /// Time left until `key` expires, or `None` if it never does.
+    pub fn ttl(&self, key: &[u8]) -> crate::Result<Option<Duration>> {
+        let now = now()?;
+        let item = self
+            .keydir
+            .read()
+            .unwrap()
+            .get(key)
+            .cloned()
+            .ok_or(Error::KeyMiss)?;
+        if item.is_expired(now) {
+            return Err(Error::KeyMiss);
+        }
+        Ok(item
+            .expiry
+            .map(|expiry| Duration::from_micros(u64::try_from(expiry - now).unwrap_or(u64::MAX))))
+    }
+
+    /// Set `key` only if its live version is still `expected`.
+    pub fn compare_and_set(
+        &self,
+        key: &[u8],
+        expected: Version,
+        val: &[u8],
+    ) -> crate::Result<Version> {
+        self.set_if(key, val, None, move |current| match current {
+            Some(current) if current == expected => Ok(()),
+            _ => Err(Error::VersionMismatch),
+        })
+    }
+
+    /// Set `key` only if it has no live value.
+    pub fn set_if_absent(&self, key: &[u8], val: &[u8]) -> crate::Result<Version> {
+        self.set_if(key, val, None, |current| match current {
+            Some(_) => Err(Error::KeyExists),
+            None => Ok(()),
+        })
+    }
+
+    /// Set `key` only if it already has a live value.
+    pub fn set_if_present(&self, key: &[u8], val: &[u8]) -> crate::Result<Version> {
+        self.set_if(key, val, None, |current| match current {
+            Some(_) => Ok(()),
+            None => Err(Error::KeyMiss),
+        })
+    }
+
+    /// Write `val` under `key` if `check` accepts the key's live version.
+    fn set_if(
+        &self,
+        key: &[u8],
+        val: &[u8],
+        expiry: Option<u128>,
+        check: impl FnOnce(Option<Version>) -> crate::Result<()> + Send + 'static,
+    ) -> crate::Result<Version> {
+        self.check_value_size(val)?;
+        self.write(Write::Single {
+            key: key.to_vec(),
+            val: Some(val.to_vec()),
+            expiry,
+            check: Box::new(check),
+        })
+    }
+
+    /// Commit `write` along with any others happening concurrently, and point the `KeyDir`
+    /// at the result.
+    fn write(&self, write: Write) -> crate::Result<Version> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        self.group_commit
+            .commit(&self.file_manager, &self.keydir, write)
+    }
+
+    fn check_value_size(&self, val: &[u8]) -> crate::Result<()> {
+        let max = self.config.max_value_size;
+        if val.len() as u64 > max {
+            return Err(Error::ValueTooLarge {
+                size: val.len() as u64,
+                max,
+            });
+        }
         Ok(())
     }
 
-    pub fn get(&self, key: &[u8]) -> crate::Result<Vec<u8>> {
-        if let Some(item) = self.keydir.read().unwrap().get(key) {
-            // TODO if we are having file problems, should we evict from the keydir?
-            let mut file_manager = self.file_manager.lock().unwrap();
-            let val = file_manager.read_item(item)?;
-            if !crate::is_tombstone(&val) {
-                return Ok(val);
-            }
+    /// Apply every operation in `batch`, such that either all or none of them persist.
+    pub fn write_batch(&self, batch: WriteBatch) -> crate::Result<()> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
         }
-        Err(KeyMiss.into())
+        if batch.is_empty() {
+            return Ok(());
+        }
+        for (_, val) in batch.ops() {
+            self.check_value_size(val.unwrap_or_default())?;
+        }
+        self.write(Write::Batch(batch)).map(|_| ())
+    }
+
+    /// Flush all acknowledged writes to disk, whatever the configured `SyncMode`.
+    pub fn sync(&self) -> crate::Result<()> {
+        self.file_manager.lock().unwrap().sync()
+    }
+
+    pub fn get(&self, key: &[u8]) -> crate::Result<Vec<u8>> {
+        read_live(&self.keydir, &self.log_files, key)?.ok_or(Error::KeyMiss)
+    }
+
+    /// Like `get`, but the value points straight into the log file's memory map where it can,
+    /// rather than being copied out of it. Values in the file currently being written to, and
+    /// compressed or encrypted ones, still get copied.
+    pub fn get_bytes(&self, key: &[u8]) -> crate::Result<Bytes> {
+        let (val, _) = read_live_item(&self.keydir, key, |item| {
+            self.log_files.read_item_bytes(item)
+        })?
+        .ok_or(Error::KeyMiss)?;
+        Ok(val)
+    }
+
+    /// Like `get`, but also return the version to pass to `compare_and_set`.
+    pub fn get_versioned(&self, key: &[u8]) -> crate::Result<(Vec<u8>, Version)> {
+        let (val, item) = read_live_item(&self.keydir, key, |item| self.log_files.read_item(item))?
+            .ok_or(Error::KeyMiss)?;
+        Ok((val, item.version()))
+    }
+
+    /// Iterate over all live keys, in byte order.
+    pub fn keys(&self) -> Keys {
+        Keys::new(self.snapshot_keys(..), self.keydir.clone())
+    }
+
+    /// Iterate over all live entries, in byte order. Values are read lazily as the iterator
+    /// advances.
+    pub fn iter(&self) -> Iter {
+        Iter::new(
+            self.snapshot_keys(..),
+            self.keydir.clone(),
+            self.log_files.clone(),
+        )
+    }
+
+    /// Count the live keys in the store.
+    pub fn len(&self) -> crate::Result<usize> {
+        let now = now()?;
+        let keydir = self.keydir.read().unwrap();
+        Ok(keydir
+            .data
+            .values()
+            .filter(|item| !item.is_expired(now))
+            .count())
+    }
+
+    pub fn is_empty(&self) -> crate::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Iterate over live entries whose keys start with `prefix`, in byte order.
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Iter {
+        let keys = self
+            .keydir
+            .read()
+            .unwrap()
+            .scan_prefix(prefix)
+            .map(|(key, _)| key.clone())
+            .collect();
+        Iter::new(keys, self.keydir.clone(), self.log_files.clone())
+    }
+
+    /// Iterate over live entries whose keys fall in `range`, in byte order.
+    pub fn range<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Iter {
+        Iter::new(
+            self.snapshot_keys(range),
+            self.keydir.clone(),
+            self.log_files.clone(),
+        )
+    }
+
+    fn snapshot_keys<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Vec<Vec<u8>> {
+        self.keydir
+            .read()
+            .unwrap()
+            .range(range)
+            .map(|(key, _)| key.clone())
+            .collect()
     }
 
     pub fn delete(&self, key: &[u8]) -> crate::Result<()> {
-        self.set(key, crate::TOMBSTONE)
+        self.write(Write::Single {
+            key: key.to_vec(),
+            val: None,
+            expiry: None,
+            check: Box::new(|_| Ok(())),
+        })
+        .map(|_| ())
     }
 
     pub fn merge(&self) -> crate::Result<()> {
-        // Take mutex to hold throughout this function's scope.
-        let _merge_mutex = self.merge_mutex.try_lock().map_err(|_| MergeUnderway)?;
-        let files_to_merge: Vec<_> = {
-            let file_manager = self.file_manager.lock().unwrap();
-            file_manager.iter_closed().map(|f| f.path.clone()).collect()
-        };
-        let (merge_keydir, merge_file_manager) =
-            merge(self.keydir.clone(), &files_to_merge, self.config.clone()).unwrap();
+        self.merge_with(&MergePolicy::default())
+    }
 
-        let mut keydir = self.keydir.write().unwrap();
-        for (key, item) in merge_keydir.data {
-            keydir.set(key, item);
+    /// Merge only the closed files picked by `policy`, leaving the rest as they are.
+    pub fn merge_with(&self, policy: &MergePolicy) -> crate::Result<()> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
         }
+        self.merger.merge(policy)
+    }
 
-        let mut file_manager = self.file_manager.lock().unwrap();
-        for path in files_to_merge {
-            if let Some(handle) = file_manager.remove(&path) {
-                std::fs::remove_file(&handle.path).unwrap();
-                let mut hint_path = handle.path.clone();
-                hint_path.set_extension("hint");
-                if hint_path.exists() {
-                    std::fs::remove_file(&hint_path).unwrap();
-                }
-            }
-        }
+    /// How much of each log file is still live, the current one included, by path.
+    pub fn file_stats(&self) -> BTreeMap<PathBuf, FileStats> {
+        let file_manager = self.file_manager.lock().unwrap();
+        file_manager
+            .iter()
+            .map(|handle| (handle.path.clone(), handle.stats()))
+            .collect()
+    }
+}
+
+/// Check whether `key` maps to a live value, without reading it.
+pub(crate) fn is_live(keydir: &SharedKeyDir, key: &[u8]) -> crate::Result<bool> {
+    let now = now()?;
+    Ok(keydir
+        .read()
+        .unwrap()
+        .get(key)
+        .is_some_and(|item| !item.is_expired(now)))
+}
+
+/// Read the live value for `key`, or `None` if it is missing or expired.
+pub(crate) fn read_live(
+    keydir: &SharedKeyDir,
+    log_files: &LogFiles,
+    key: &[u8],
+) -> crate::Result<Option<Vec<u8>>> {
+    Ok(read_live_item(keydir, key, |item| log_files.read_item(item))?.map(|(val, _)| val))
+}
 
-        for (_, handle) in merge_file_manager.inner {
-            file_manager.insert(handle);
+/// Like `read_live`, but read the value with `read`, and also return the `Item` it was read
+/// from.
+fn read_live_item<T>(
+    keydir: &SharedKeyDir,
+    key: &[u8],
+    read: impl Fn(&Item) -> crate::Result<T>,
+) -> crate::Result<Option<(T, Item)>> {
+    let now = now()?;
+    let mut item = match keydir.read().unwrap().get(key) {
+        Some(item) => item.clone(),
+        None => return Ok(None),
+    };
+    loop {
+        if item.is_expired(now) {
+            return Ok(None);
+        }
+        // TODO if we are having file problems, should we evict from the keydir?
+        match read(&item) {
+            Ok(val) => return Ok(Some((val, item))),
+            // A `merge` may have moved the value to another file in the meantime.
+            Err(e) => match keydir.read().unwrap().get(key) {
+                Some(current) if *current != item => item = current.clone(),
+                Some(_) => return Err(e),
+                None => return Ok(None),
+            },
         }
+    }
+}
 
-        Ok(())
+/// Record `item` as the write of `key` to go by, unless a later one was already seen.
+fn keep_latest(
+    latest: &mut BTreeMap<Vec<u8>, (Item, bool)>,
+    key: Vec<u8>,
+    item: Item,
+    deleted: bool,
+) {
+    // Entries sharing a timestamp come from the same batch, where the last one wins.
+    if latest.get(&key).is_some_and(|(seen, _)| seen.ts > item.ts) {
+        return;
     }
+    latest.insert(key, (item, deleted));
 }
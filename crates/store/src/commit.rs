@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+
+use crate::batch::WriteBatch;
+use crate::keydir::{KeyDir, Version};
+use crate::log::files::FileManager;
+use crate::log::{now, LogEntry};
+use crate::Result;
+
+/// Decides from the live version of a key, if any, whether a write to it goes ahead.
+pub(crate) type Check = Box<dyn FnOnce(Option<Version>) -> Result<()> + Send>;
+
+/// A write waiting to be committed.
+pub(crate) enum Write {
+    /// Set `key` to `val`, or delete it if there's no `val`, provided `check` passes.
+    Single {
+        key: Vec<u8>,
+        val: Option<Vec<u8>>,
+        expiry: Option<u128>,
+        check: Check,
+    },
+    Batch(WriteBatch),
+}
+
+type Slot = Mutex<Option<Result<Version>>>;
+
+/// Lets concurrent writers share the cost of writing to the log and syncing it.
+///
+/// Every write gets queued, and whichever writer gets hold of the `FileManager` next commits
+/// everything queued up by then as a group: in as few writes to the log as rotation allows,
+/// followed by a single sync. Writers whose write was committed by the time they get the lock
+/// just pick up the result.
+#[derive(Default)]
+pub(crate) struct GroupCommit {
+    queue: Mutex<Vec<(Write, Arc<Slot>)>>,
+}
+
+impl GroupCommit {
+    /// Commit `write`, along with whatever else is queued up, and point the `KeyDir` at it.
+    pub fn commit(
+        &self,
+        file_manager: &Mutex<FileManager>,
+        keydir: &RwLock<KeyDir>,
+        write: Write,
+    ) -> Result<Version> {
+        let slot = Arc::new(Slot::default());
+        self.queue.lock().unwrap().push((write, slot.clone()));
+        let mut file_manager = file_manager.lock().unwrap();
+        if let Some(result) = slot.lock().unwrap().take() {
+            return result;
+        }
+        // Nobody took the write off the queue before this thread got the lock, so it's up to
+        // this thread to commit it, and everything queued up since.
+        let group = std::mem::take(&mut *self.queue.lock().unwrap());
+        commit_group(&mut file_manager, keydir, group);
+        let result = slot.lock().unwrap().take();
+        result.expect("a group includes the write of the thread committing it")
+    }
+}
+
+/// A write that passed its checks, ready to go out.
+struct Prepared {
+    version: Version,
+    /// Every key written, and whether it was deleted.
+    keys: Vec<(Vec<u8>, bool)>,
+    entries: Vec<LogEntry>,
+}
+
+fn commit_group(
+    file_manager: &mut FileManager,
+    keydir: &RwLock<KeyDir>,
+    group: Vec<(Write, Arc<Slot>)>,
+) {
+    let mut overlay = Overlay::default();
+    let mut prepared = Vec::with_capacity(group.len());
+    for (write, slot) in group {
+        match prepare(file_manager, keydir, &mut overlay, write) {
+            Ok(write) => prepared.push((write, slot)),
+            Err(e) => *slot.lock().unwrap() = Some(Err(e)),
+        }
+    }
+
+    let units: Vec<_> = prepared
+        .iter()
+        .map(|(write, _)| write.entries.as_slice())
+        .collect();
+    let items = match file_manager.set_all(&units) {
+        Ok(items) => items,
+        Err(e) => {
+            for (_, slot) in prepared {
+                *slot.lock().unwrap() = Some(Err(e.duplicate()));
+            }
+            return;
+        }
+    };
+    let mut keydir = keydir.write().unwrap();
+    for ((write, slot), items) in prepared.into_iter().zip(items) {
+        for ((key, deleted), item) in write.keys.into_iter().zip(items) {
+            file_manager.count_written(&item, !deleted);
+            let superseded = if deleted {
+                keydir.remove(&key)
+            } else {
+                keydir.set(key, item)
+            };
+            if let Some(superseded) = superseded {
+                file_manager.count_superseded(&superseded);
+            }
+        }
+        *slot.lock().unwrap() = Some(Ok(write.version));
+    }
+}
+
+/// Stamp `write` with a timestamp, check it against the live version of its key, and encode
+/// it for the log.
+fn prepare(
+    file_manager: &mut FileManager,
+    keydir: &RwLock<KeyDir>,
+    overlay: &mut Overlay,
+    write: Write,
+) -> Result<Prepared> {
+    let ts = file_manager.next_ts()?;
+    let entries = match write {
+        Write::Single {
+            key,
+            val,
+            expiry,
+            check,
+        } => {
+            check(overlay.live_version(keydir, &key)?)?;
+            let mut entry = match val {
+                Some(val) => LogEntry::from_set(&key, &val, ts),
+                None => LogEntry::tombstone(&key, ts),
+            };
+            entry.expiry = expiry;
+            vec![entry]
+        }
+        Write::Batch(batch) => batch.into_entries(ts),
+    };
+    let version = Version(ts);
+    let live: Vec<_> = entries
+        .iter()
+        .map(|entry| (!entry.is_tombstone()).then_some((version, entry.expiry)))
+        .collect();
+    let keys: Vec<_> = entries
+        .iter()
+        .map(|entry| (entry.key.clone(), entry.is_tombstone()))
+        .collect();
+    let entries = entries
+        .into_iter()
+        .map(|entry| file_manager.encode(entry))
+        .collect::<Result<_>>()?;
+    for ((key, _), live) in keys.iter().zip(live) {
+        overlay.pending.insert(key.clone(), live);
+    }
+    Ok(Prepared {
+        version,
+        keys,
+        entries,
+    })
+}
+
+/// The `KeyDir` as it'll look once the writes accepted into a group so far are committed, so
+/// that checks take earlier writes in the same group into account.
+#[derive(Default)]
+struct Overlay {
+    /// Version and expiry of keys written in the group, or `None` where they were deleted.
+    pending: HashMap<Vec<u8>, Option<(Version, Option<u128>)>>,
+}
+
+impl Overlay {
+    fn live_version(&self, keydir: &RwLock<KeyDir>, key: &[u8]) -> Result<Option<Version>> {
+        let now = now()?;
+        let live = match self.pending.get(key) {
+            Some(pending) => *pending,
+            None => keydir
+                .read()
+                .unwrap()
+                .get(key)
+                .map(|item| (item.version(), item.expiry)),
+        };
+        Ok(live
+            .filter(|(_, expiry)| !expiry.is_some_and(|expiry| expiry <= now))
+            .map(|(version, _)| version))
+    }
+}
@@ -3,10 +3,170 @@ use std::path::PathBuf;
 use ::config::{Config, ConfigError};
 use serde::Deserialize;
 
-#[derive(Debug, Deserialize)]
+/// When writes get flushed from the OS page cache to the disk.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncMode {
+    /// Sync before acknowledging every write.
+    Always,
+    /// Sync from a background thread every `sync_interval_ms`.
+    Interval,
+    /// Never sync explicitly, leaving it to the OS.
+    #[default]
+    Os,
+}
+
+/// What to do on finding a corrupt entry while loading the store or merging.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CorruptionPolicy {
+    /// Give up with an error.
+    #[default]
+    Fail,
+    /// Log a warning and carry on without the entry.
+    Skip,
+    /// Move the whole file into `quarantine/` in the log directory, and carry on without it.
+    Quarantine,
+}
+
+/// How values get compressed on disk. Every algorithm but `None` needs the cargo feature of the
+/// same name.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    Lz4,
+    Zstd,
+}
+
+impl Compression {
+    /// Check that support for the algorithm was compiled in.
+    pub fn check_available(self) -> crate::Result<()> {
+        let available = match self {
+            Compression::None => true,
+            Compression::Lz4 => cfg!(feature = "lz4"),
+            Compression::Zstd => cfg!(feature = "zstd"),
+        };
+        if !available {
+            return Err(crate::Error::InvalidConfig(format!(
+                "{:?} compression needs the store to be built with its feature",
+                self
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
 pub struct StoreConfig {
     pub log_dir: PathBuf,
+    /// Size at which log files get rotated. A single value bigger than this gets a file to
+    /// itself.
     pub max_log_file_size: u64,
+    /// Writes of larger values are rejected with `Error::ValueTooLarge`.
+    #[serde(default = "default_max_value_size")]
+    pub max_value_size: u64,
+    #[serde(default)]
+    pub sync_mode: SyncMode,
+    #[serde(default = "default_sync_interval_ms")]
+    pub sync_interval_ms: u64,
+    #[serde(default)]
+    pub corruption_policy: CorruptionPolicy,
+    #[serde(default)]
+    pub compression: Compression,
+    /// Values shorter than this are stored uncompressed, as they'd barely shrink if at all.
+    #[serde(default = "default_compression_min_size")]
+    pub compression_min_size: usize,
+    /// Hex-encoded 256-bit key to encrypt entries and hints with. Unset means new entries are
+    /// written in the clear.
+    #[serde(default)]
+    pub encryption_key: Option<String>,
+    /// File holding the hex-encoded key instead, as an alternative to `encryption_key`.
+    #[serde(default)]
+    pub encryption_key_file: Option<PathBuf>,
+    /// Keys entries may still be encrypted under after a rotation. They're only used for
+    /// reading, and `merge` rewrites everything under the current key.
+    #[serde(default)]
+    pub retired_encryption_keys: Vec<String>,
+    /// Merge from a background thread whenever one of the `merge_*_trigger`s fires.
+    #[serde(default)]
+    pub auto_merge: bool,
+    /// How often the background thread checks the triggers.
+    #[serde(default = "default_merge_check_interval_ms")]
+    pub merge_check_interval_ms: u64,
+    /// Merge once any closed file is at least this many percent dead bytes.
+    #[serde(default = "default_merge_frag_trigger")]
+    pub merge_frag_trigger: u8,
+    /// Merge once the closed files hold at least this many dead bytes between them.
+    #[serde(default = "default_merge_dead_bytes_trigger")]
+    pub merge_dead_bytes_trigger: u64,
+    /// Merge once at least this many closed files hold any dead entries, if set.
+    #[serde(default)]
+    pub merge_closed_files_trigger: Option<usize>,
+    /// Hour of the day, in UTC, from which automatic merges may start. Set along with
+    /// `merge_window_end_hour`, or leave both unset to merge at any time.
+    #[serde(default)]
+    pub merge_window_start_hour: Option<u8>,
+    /// Hour of the day, in UTC, from which automatic merges may no longer start. A window
+    /// ending before it starts wraps around midnight.
+    #[serde(default)]
+    pub merge_window_end_hour: Option<u8>,
+}
+
+impl StoreConfig {
+    /// Check that the automatic merge settings make sense.
+    pub fn check_merge_policy(&self) -> crate::Result<()> {
+        let invalid = |msg: &str| Err(crate::Error::InvalidConfig(msg.to_string()));
+        if self.merge_frag_trigger > 100 {
+            return invalid("merge_frag_trigger is a percentage, so can't be over 100");
+        }
+        if self.merge_check_interval_ms == 0 {
+            return invalid("merge_check_interval_ms must be above 0");
+        }
+        match (self.merge_window_start_hour, self.merge_window_end_hour) {
+            (Some(start), Some(end)) if start >= 24 || end >= 24 => {
+                invalid("The merge window's hours must be below 24")
+            }
+            (Some(_), None) | (None, Some(_)) => {
+                invalid("Set both merge_window_start_hour and merge_window_end_hour, or neither")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether automatic merges may start at `hour` of the day, in UTC.
+    pub fn in_merge_window(&self, hour: u8) -> bool {
+        match (self.merge_window_start_hour, self.merge_window_end_hour) {
+            (Some(start), Some(end)) if start <= end => (start..end).contains(&hour),
+            (Some(start), Some(end)) => hour >= start || hour < end,
+            _ => true,
+        }
+    }
+}
+
+fn default_max_value_size() -> u64 {
+    1 << 30
+}
+
+fn default_sync_interval_ms() -> u64 {
+    1000
+}
+
+fn default_compression_min_size() -> usize {
+    64
+}
+
+fn default_merge_check_interval_ms() -> u64 {
+    180_000
+}
+
+fn default_merge_frag_trigger() -> u8 {
+    60
+}
+
+fn default_merge_dead_bytes_trigger() -> u64 {
+    512 << 20
 }
 
 impl Default for StoreConfig {
@@ -14,6 +174,22 @@ impl Default for StoreConfig {
         Self {
             log_dir: "/tmp/bitcask/".into(),
             max_log_file_size: 2_000_000_000,
+            max_value_size: default_max_value_size(),
+            sync_mode: SyncMode::default(),
+            sync_interval_ms: default_sync_interval_ms(),
+            corruption_policy: CorruptionPolicy::default(),
+            compression: Compression::default(),
+            compression_min_size: default_compression_min_size(),
+            encryption_key: None,
+            encryption_key_file: None,
+            retired_encryption_keys: Vec::new(),
+            auto_merge: false,
+            merge_check_interval_ms: default_merge_check_interval_ms(),
+            merge_frag_trigger: default_merge_frag_trigger(),
+            merge_dead_bytes_trigger: default_merge_dead_bytes_trigger(),
+            merge_closed_files_trigger: None,
+            merge_window_start_hour: None,
+            merge_window_end_hour: None,
         }
     }
 }
@@ -24,6 +200,22 @@ pub fn get_store_config() -> Result<StoreConfig, ConfigError> {
         .add_source(config::Environment::with_prefix("BITCASK").try_parsing(true))
         .set_default("log_dir", "/tmp/bitcask/")?
         .set_default("max_log_file_size", 25_000_000)?
+        .set_default("max_value_size", default_max_value_size())?
+        .set_default("sync_mode", "os")?
+        .set_default("sync_interval_ms", default_sync_interval_ms())?
+        .set_default("corruption_policy", "fail")?
+        .set_default("compression", "none")?
+        .set_default(
+            "compression_min_size",
+            default_compression_min_size() as u64,
+        )?
+        .set_default("auto_merge", false)?
+        .set_default("merge_check_interval_ms", default_merge_check_interval_ms())?
+        .set_default("merge_frag_trigger", default_merge_frag_trigger() as u64)?
+        .set_default(
+            "merge_dead_bytes_trigger",
+            default_merge_dead_bytes_trigger(),
+        )?
         .build()?;
     // TODO would be good to validate that the provided values make sense.
     config.try_deserialize()
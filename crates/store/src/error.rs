@@ -0,0 +1,145 @@
+use std::fmt;
+use std::path::PathBuf;
+
+#[derive(Debug)]
+pub enum Error {
+    /// No live value is stored under the requested key.
+    KeyMiss,
+    /// A conditional write expected the key to be absent, but it holds a live value.
+    KeyExists,
+    /// A `compare_and_set` expected a different version of the key than the live one.
+    VersionMismatch,
+    /// Another `merge` is already running on this store.
+    MergeUnderway,
+    /// The entry at `offset` in `path` does not match its checksum.
+    CrcMismatch {
+        path: PathBuf,
+        offset: u64,
+    },
+    /// The entry at `offset` in `path` ends before its declared length.
+    TruncatedEntry {
+        path: PathBuf,
+        offset: u64,
+    },
+    /// The file at `path` was written in a format version this build doesn't know.
+    UnsupportedFormat {
+        path: PathBuf,
+        version: u16,
+    },
+    /// A write's value of `size` bytes is over the configured `max_value_size`.
+    ValueTooLarge {
+        size: u64,
+        max: u64,
+    },
+    /// The store was opened with `BitCask::open_read_only` and can't be written to.
+    ReadOnly,
+    /// Another `BitCask` already has the log directory at `path` open.
+    Locked {
+        path: PathBuf,
+        pid: Option<u32>,
+    },
+    /// The entry at `offset` in `path` is encrypted, but none of the configured keys can
+    /// decrypt it, or it was tampered with.
+    Decryption {
+        path: PathBuf,
+        offset: u64,
+    },
+    InvalidConfig(String),
+    Io(std::io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::KeyMiss => write!(f, "Key miss"),
+            Error::KeyExists => write!(f, "Key already exists"),
+            Error::VersionMismatch => write!(f, "Version mismatch"),
+            Error::MergeUnderway => write!(f, "Merge already underway!"),
+            Error::CrcMismatch { path, offset } => {
+                write!(f, "CRC mismatch in {:?} at offset {}", path, offset)
+            }
+            Error::TruncatedEntry { path, offset } => {
+                write!(f, "Truncated entry in {:?} at offset {}", path, offset)
+            }
+            Error::UnsupportedFormat { path, version } => {
+                write!(f, "Unsupported format version {} in {:?}", version, path)
+            }
+            Error::ValueTooLarge { size, max } => {
+                write!(f, "Value of {} bytes exceeds the maximum of {}", size, max)
+            }
+            Error::ReadOnly => write!(f, "Store is open read-only"),
+            Error::Locked {
+                path,
+                pid: Some(pid),
+            } => write!(f, "{:?} is locked by process {}", path, pid),
+            Error::Locked { path, pid: None } => {
+                write!(f, "{:?} is locked by another process", path)
+            }
+            Error::Decryption { path, offset } => {
+                write!(f, "Can't decrypt entry in {:?} at offset {}", path, offset)
+            }
+            Error::InvalidConfig(msg) => write!(f, "Invalid config: {}", msg),
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl Error {
+    /// A copy to report to each of several callers failing for the same reason. I/O errors
+    /// keep their kind and message, but not their source.
+    pub(crate) fn duplicate(&self) -> Self {
+        match self {
+            Error::KeyMiss => Error::KeyMiss,
+            Error::KeyExists => Error::KeyExists,
+            Error::VersionMismatch => Error::VersionMismatch,
+            Error::MergeUnderway => Error::MergeUnderway,
+            Error::CrcMismatch { path, offset } => Error::CrcMismatch {
+                path: path.clone(),
+                offset: *offset,
+            },
+            Error::TruncatedEntry { path, offset } => Error::TruncatedEntry {
+                path: path.clone(),
+                offset: *offset,
+            },
+            Error::UnsupportedFormat { path, version } => Error::UnsupportedFormat {
+                path: path.clone(),
+                version: *version,
+            },
+            Error::ValueTooLarge { size, max } => Error::ValueTooLarge {
+                size: *size,
+                max: *max,
+            },
+            Error::ReadOnly => Error::ReadOnly,
+            Error::Locked { path, pid } => Error::Locked {
+                path: path.clone(),
+                pid: *pid,
+            },
+            Error::Decryption { path, offset } => Error::Decryption {
+                path: path.clone(),
+                offset: *offset,
+            },
+            Error::InvalidConfig(msg) => Error::InvalidConfig(msg.clone()),
+            Error::Io(e) => Error::io(e.kind(), e.to_string()),
+        }
+    }
+
+    /// Shorthand for I/O failures that don't originate in `std::io`.
+    pub(crate) fn io(kind: std::io::ErrorKind, msg: impl Into<String>) -> Self {
+        Error::Io(std::io::Error::new(kind, msg.into()))
+    }
+}
@@ -0,0 +1,104 @@
+use std::sync::Arc;
+
+use crate::bitcask::{is_live, read_live, SharedKeyDir};
+use crate::log::files::LogFiles;
+use crate::Result;
+
+/// Lazily reads the values for a snapshot of keys, in the order they were taken.
+///
+/// Keys deleted after the snapshot are skipped, and values are always read as of the time
+/// each key is reached.
+pub struct Iter {
+    keys: std::vec::IntoIter<Vec<u8>>,
+    keydir: SharedKeyDir,
+    log_files: Arc<LogFiles>,
+}
+
+impl Iter {
+    pub(crate) fn new(keys: Vec<Vec<u8>>, keydir: SharedKeyDir, log_files: Arc<LogFiles>) -> Self {
+        Self {
+            keys: keys.into_iter(),
+            keydir,
+            log_files,
+        }
+    }
+
+    fn read(&self, key: Vec<u8>) -> Option<Result<(Vec<u8>, Vec<u8>)>> {
+        match read_live(&self.keydir, &self.log_files, &key) {
+            Ok(Some(val)) => Some(Ok((key, val))),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+impl Iterator for Iter {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(key) = self.keys.next() {
+            if let Some(read) = self.read(key) {
+                return Some(read);
+            }
+        }
+        None
+    }
+}
+
+impl DoubleEndedIterator for Iter {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        while let Some(key) = self.keys.next_back() {
+            if let Some(read) = self.read(key) {
+                return Some(read);
+            }
+        }
+        None
+    }
+}
+
+/// Like `Iter`, but only yields the keys, so no values are read at all.
+pub struct Keys {
+    keys: std::vec::IntoIter<Vec<u8>>,
+    keydir: SharedKeyDir,
+}
+
+impl Keys {
+    pub(crate) fn new(keys: Vec<Vec<u8>>, keydir: SharedKeyDir) -> Self {
+        Self {
+            keys: keys.into_iter(),
+            keydir,
+        }
+    }
+
+    fn read(&self, key: Vec<u8>) -> Option<Result<Vec<u8>>> {
+        match is_live(&self.keydir, &key) {
+            Ok(true) => Some(Ok(key)),
+            Ok(false) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+impl Iterator for Keys {
+    type Item = Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(key) = self.keys.next() {
+            if let Some(read) = self.read(key) {
+                return Some(read);
+            }
+        }
+        None
+    }
+}
+
+impl DoubleEndedIterator for Keys {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        while let Some(key) = self.keys.next_back() {
+            if let Some(read) = self.read(key) {
+                return Some(read);
+            }
+        }
+        None
+    }
+}
@@ -1,23 +1,55 @@
-use std::collections::HashMap;
+use std::collections::BTreeMap;
+use std::ops::{Bound, RangeBounds};
 use std::path::PathBuf;
 
+use crate::log::format::Format;
+use crate::log::{encode_size, encode_ts};
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Item {
     pub path: PathBuf,
+    /// Size of the key as stored, which encryption makes bigger than the key itself.
+    pub key_sz: usize,
     pub val_sz: usize,
     pub val_pos: u64,
     pub ts: u128,
+    /// When the value stops being readable, in microseconds since the epoch.
+    pub expiry: Option<u128>,
 }
 
+/// Identifies one particular write of a key, for use with the conditional writes on `BitCask`.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Version(pub(crate) u128);
+
 impl Item {
-    // TODO key should be as bytes! len may be off.
-    pub fn serialize_as_hint(&self, key: &[u8]) -> Vec<u8> {
-        let key_sz = key.len();
-        let mut serialized = Vec::with_capacity(16 + 3 * 8 + key_sz);
-        serialized.extend(self.ts.to_ne_bytes());
-        serialized.extend((key_sz as u64).to_ne_bytes());
-        serialized.extend(self.val_sz.to_ne_bytes());
-        serialized.extend(self.val_pos.to_ne_bytes());
+    pub fn version(&self) -> Version {
+        Version(self.ts)
+    }
+
+    pub fn is_expired(&self, now: u128) -> bool {
+        self.expiry.is_some_and(|expiry| expiry <= now)
+    }
+
+    /// Size of the entry in a file of `format`.
+    pub fn entry_sz(&self, format: Format) -> u64 {
+        let header_sz = format.encode_u32(0).len()
+            + encode_ts(format, self.ts, 0, self.expiry).len()
+            + encode_size(format, self.key_sz as u64).len()
+            + encode_size(format, self.val_sz as u64).len();
+        (header_sz + self.key_sz + self.val_sz) as u64
+    }
+
+    /// Serialize as a hint record for `key`, carrying the `flags` of the entry it points at.
+    pub fn serialize_as_hint(&self, key: &[u8], flags: u8) -> Vec<u8> {
+        self.serialize_as_hint_in(Format::CURRENT, key, flags)
+    }
+
+    /// Like `serialize_as_hint`, but the way hint files in `format` lay it out.
+    pub fn serialize_as_hint_in(&self, format: Format, key: &[u8], flags: u8) -> Vec<u8> {
+        let mut serialized = encode_ts(format, self.ts, flags, self.expiry);
+        serialized.extend(encode_size(format, key.len() as u64));
+        serialized.extend(encode_size(format, self.val_sz as u64));
+        serialized.extend(encode_size(format, self.val_pos));
         serialized.extend(key);
         serialized
     }
@@ -26,7 +58,7 @@ impl Item {
 #[derive(Clone, Debug, Default)]
 pub struct KeyDir {
     // TODO really shouldnt be `pub`
-    pub data: HashMap<Vec<u8>, Item>,
+    pub data: BTreeMap<Vec<u8>, Item>,
 }
 
 impl KeyDir {
@@ -34,7 +66,38 @@ impl KeyDir {
         self.data.get(key)
     }
 
-    pub fn set(&mut self, key: Vec<u8>, item: Item) {
-        self.data.insert(key, item);
+    /// Point `key` at `item`, returning the item it pointed at before, if any.
+    pub fn set(&mut self, key: Vec<u8>, item: Item) -> Option<Item> {
+        self.data.insert(key, item)
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Option<Item> {
+        self.data.remove(key)
+    }
+
+    /// Iterate over the entries whose keys fall in `range`, in byte order.
+    pub fn range<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+    ) -> impl DoubleEndedIterator<Item = (&Vec<u8>, &Item)> {
+        self.data.range(range)
+    }
+
+    /// Iterate over the entries whose keys start with `prefix`, in byte order.
+    pub fn scan_prefix(&self, prefix: &[u8]) -> impl DoubleEndedIterator<Item = (&Vec<u8>, &Item)> {
+        self.data
+            .range((Bound::Included(prefix.to_vec()), prefix_upper_bound(prefix)))
+    }
+}
+
+/// Smallest key greater than every key starting with `prefix`, if there is one.
+fn prefix_upper_bound(prefix: &[u8]) -> Bound<Vec<u8>> {
+    let mut upper = prefix.to_vec();
+    while let Some(last) = upper.pop() {
+        if last < u8::MAX {
+            upper.push(last + 1);
+            return Bound::Excluded(upper);
+        }
     }
+    Bound::Unbounded
 }
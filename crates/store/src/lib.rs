@@ -1,20 +1,24 @@
+pub use batch::WriteBatch;
 pub use bitcask::BitCask;
-pub use merge::MergeResult;
+pub use error::Error;
+pub use iter::{Iter, Keys};
+pub use keydir::Version;
+pub use log::files::FileStats;
+pub use merge::{MergePolicy, MergeResult};
 
-pub use crate::config::{get_store_config, StoreConfig};
+pub use crate::config::{get_store_config, Compression, CorruptionPolicy, StoreConfig, SyncMode};
 
+pub mod batch;
 pub mod bitcask;
+mod commit;
 pub mod config;
+pub mod error;
+pub mod iter;
 pub mod keydir;
+mod lock;
 pub mod log;
 pub mod merge;
+mod scheduler;
+mod sync;
 
-pub type Error = Box<dyn std::error::Error>;
 pub type Result<T> = std::result::Result<T, Error>;
-
-// TODO I guess I could pick a smaller tombstone (1 byte)
-pub(crate) const TOMBSTONE: &[u8; 3] = b"\xE2\x98\x97";
-
-pub(crate) fn is_tombstone(bytes: &[u8]) -> bool {
-    bytes == TOMBSTONE
-}
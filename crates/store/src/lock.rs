@@ -0,0 +1,54 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use fs2::FileExt;
+
+use crate::error::Error;
+use crate::Result;
+
+/// Name of the lock file taken in the log directory.
+pub const LOCK_FILE: &str = "bitcask.lock";
+
+/// Advisory lock making sure only one `BitCask` at a time writes to a log directory, whether
+/// from this process or another. Released when dropped.
+#[derive(Debug)]
+pub(crate) struct DirLock {
+    file: File,
+}
+
+impl DirLock {
+    pub fn acquire(log_dir: &Path) -> Result<Self> {
+        let mut file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(log_dir.join(LOCK_FILE))?;
+        if file.try_lock_exclusive().is_err() {
+            // Windows won't let the file be read while another handle has it locked, so the
+            // holder goes unnamed there.
+            let mut pid = String::new();
+            let pid = match file.read_to_string(&mut pid) {
+                Ok(_) => pid.trim().parse().ok(),
+                Err(_) => None,
+            };
+            return Err(Error::Locked {
+                path: log_dir.to_path_buf(),
+                pid,
+            });
+        }
+        // Leave the PID of the holder behind for anyone else trying their luck.
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        write!(file, "{}", std::process::id())?;
+        file.sync_data()?;
+        Ok(Self { file })
+    }
+}
+
+impl Drop for DirLock {
+    fn drop(&mut self) {
+        let _ = self.file.unlock();
+    }
+}
@@ -0,0 +1,97 @@
+use std::io::ErrorKind;
+
+use crate::config::Compression;
+use crate::error::Error;
+use crate::log::{flags, LogEntry};
+use crate::Result;
+
+impl LogEntry {
+    /// The algorithm the value is stored compressed with.
+    pub fn compression(&self) -> Compression {
+        if self.flags & flags::LZ4 != 0 {
+            Compression::Lz4
+        } else if self.flags & flags::ZSTD != 0 {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    }
+
+    /// Store the value compressed with `compression`, as long as it's at least `min_size` bytes
+    /// and actually shrinks. A value already compressed some other way gets decompressed
+    /// first, so that `merge` can bring old entries in line with the current settings.
+    pub(crate) fn compress(self, compression: Compression, min_size: usize) -> Result<Self> {
+        if self.compression() == compression {
+            return Ok(self);
+        }
+        let mut entry = self.decompress()?;
+        // Neither deletes nor batch markers carry a value to speak of.
+        let markers = flags::TOMBSTONE | flags::BATCH_COMMIT;
+        if compression == Compression::None
+            || entry.flags & markers != 0
+            || entry.val.len() < min_size
+        {
+            return Ok(entry);
+        }
+        let compressed = compress_with(compression, &entry.val)?;
+        if compressed.len() < entry.val.len() {
+            entry.val = compressed;
+            entry.flags |= flag_for(compression);
+        }
+        Ok(entry)
+    }
+
+    /// Restore the value to the way it was written.
+    pub(crate) fn decompress(mut self) -> Result<Self> {
+        let compression = self.compression();
+        if compression != Compression::None {
+            self.val = decompress_with(compression, &self.val)?;
+            self.flags &= !flag_for(compression);
+        }
+        Ok(self)
+    }
+}
+
+fn flag_for(compression: Compression) -> u8 {
+    match compression {
+        Compression::None => 0,
+        Compression::Lz4 => flags::LZ4,
+        Compression::Zstd => flags::ZSTD,
+    }
+}
+
+fn compress_with(compression: Compression, val: &[u8]) -> Result<Vec<u8>> {
+    match compression {
+        Compression::None => Ok(val.to_vec()),
+        #[cfg(feature = "lz4")]
+        Compression::Lz4 => Ok(lz4_flex::compress_prepend_size(val)),
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => Ok(zstd::bulk::compress(val, 0)?),
+        #[allow(unreachable_patterns)]
+        _ => Err(unavailable(compression)),
+    }
+}
+
+fn decompress_with(compression: Compression, val: &[u8]) -> Result<Vec<u8>> {
+    match compression {
+        Compression::None => Ok(val.to_vec()),
+        #[cfg(feature = "lz4")]
+        Compression::Lz4 => lz4_flex::decompress_size_prepended(val)
+            .map_err(|e| Error::io(ErrorKind::InvalidData, e.to_string())),
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => Ok(zstd::stream::decode_all(val)?),
+        #[allow(unreachable_patterns)]
+        _ => Err(unavailable(compression)),
+    }
+}
+
+#[allow(dead_code)]
+fn unavailable(compression: Compression) -> Error {
+    Error::io(
+        ErrorKind::Unsupported,
+        format!(
+            "Value compressed with {:?}, but its feature is off",
+            compression
+        ),
+    )
+}
@@ -0,0 +1,181 @@
+use std::fmt;
+use std::path::Path;
+
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use sha2::{Digest, Sha256};
+
+use crate::config::StoreConfig;
+use crate::error::Error;
+use crate::log::format::Format;
+use crate::log::{encode_ts, flags, LogEntry};
+use crate::Result;
+
+/// Size of the random nonce stored in front of every encrypted field. It's long enough for
+/// random nonces to never realistically repeat under one key.
+const NONCE_SZ: usize = 24;
+
+/// The keys entries are encrypted and decrypted with.
+#[derive(Default)]
+pub(crate) struct Keyring {
+    /// Key for new entries, if encryption is on.
+    current: Option<XChaCha20Poly1305>,
+    /// Keys that are only tried when the current one fails, for entries from before a rotation.
+    retired: Vec<XChaCha20Poly1305>,
+}
+
+impl fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Keyring")
+            .field("encrypting", &self.current.is_some())
+            .field("retired", &self.retired.len())
+            .finish()
+    }
+}
+
+impl Keyring {
+    pub(crate) fn from_config(config: &StoreConfig) -> Result<Self> {
+        let current = match (&config.encryption_key, &config.encryption_key_file) {
+            (Some(_), Some(_)) => {
+                return Err(Error::InvalidConfig(
+                    "Set either encryption_key or encryption_key_file, not both".to_string(),
+                ))
+            }
+            (Some(key), None) => Some(cipher(key)?),
+            (None, Some(path)) => Some(cipher(&read_key_file(path)?)?),
+            (None, None) => None,
+        };
+        let retired = config
+            .retired_encryption_keys
+            .iter()
+            .map(|key| cipher(key))
+            .collect::<Result<_>>()?;
+        Ok(Self { current, retired })
+    }
+
+    /// Encrypt the key and value of `entry` under the current key, or leave it be if there's
+    /// none. Expects an entry that isn't encrypted yet.
+    pub(crate) fn encrypt(&self, mut entry: LogEntry) -> Result<LogEntry> {
+        let Some(cipher) = &self.current else {
+            return Ok(entry);
+        };
+        // Batch markers hold nothing but a count.
+        if entry.flags & flags::BATCH_COMMIT != 0 {
+            return Ok(entry);
+        }
+        entry.flags |= flags::ENCRYPTED;
+        entry.key = seal(cipher, &key_aad(entry.ts), &entry.key)?;
+        if !entry.val.is_empty() {
+            entry.val = seal(cipher, &val_aad(&entry), &entry.val)?;
+        }
+        Ok(entry)
+    }
+
+    /// Decrypt the key and value of `entry`, which was read from `offset` in `path`, if they're
+    /// encrypted.
+    pub(crate) fn decrypt(
+        &self,
+        mut entry: LogEntry,
+        path: &Path,
+        offset: u64,
+    ) -> Result<LogEntry> {
+        if entry.flags & flags::ENCRYPTED == 0 {
+            return Ok(entry);
+        }
+        // The value's AAD covers the key as sealed, so it has to be opened first.
+        if !entry.val.is_empty() {
+            entry.val = self.open(&val_aad(&entry), &entry.val, path, offset)?;
+        }
+        entry.key = self.open(&key_aad(entry.ts), &entry.key, path, offset)?;
+        entry.flags &= !flags::ENCRYPTED;
+        Ok(entry)
+    }
+
+    /// Decrypt just the key of an entry with timestamp `ts` and `flags`, as stored in a hint
+    /// file.
+    pub(crate) fn decrypt_key(
+        &self,
+        key: Vec<u8>,
+        ts: u128,
+        flags: u8,
+        path: &Path,
+        offset: u64,
+    ) -> Result<Vec<u8>> {
+        if flags & flags::ENCRYPTED == 0 {
+            return Ok(key);
+        }
+        self.open(&key_aad(ts), &key, path, offset)
+    }
+
+    fn open(&self, aad: &[u8], sealed: &[u8], path: &Path, offset: u64) -> Result<Vec<u8>> {
+        let error = || Error::Decryption {
+            path: path.to_path_buf(),
+            offset,
+        };
+        if sealed.len() < NONCE_SZ {
+            return Err(error());
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_SZ);
+        self.current
+            .iter()
+            .chain(&self.retired)
+            .find_map(|cipher| {
+                let payload = Payload {
+                    msg: ciphertext,
+                    aad,
+                };
+                cipher.decrypt(XNonce::from_slice(nonce), payload).ok()
+            })
+            .ok_or_else(error)
+    }
+}
+
+/// Tell keys and values apart in the authentication tag, so that one can't pass for the other.
+const KEY_DOMAIN: &[u8] = b"key";
+const VAL_DOMAIN: &[u8] = b"val";
+
+/// What a key is authenticated along with. Just the timestamp, since hint files don't carry
+/// the rest of the entry for keys to be checked against on startup.
+fn key_aad(ts: u128) -> Vec<u8> {
+    [KEY_DOMAIN, &ts.to_le_bytes()].concat()
+}
+
+/// What a value is authenticated along with: the entry's header as written, so its flags and
+/// expiry can't be changed, and a hash of its sealed key, so it can't be moved to another key,
+/// even one written in the same batch.
+fn val_aad(entry: &LogEntry) -> Vec<u8> {
+    let header = encode_ts(Format::CURRENT, entry.ts, entry.flags, entry.expiry);
+    [VAL_DOMAIN, &header, &Sha256::digest(&entry.key)].concat()
+}
+
+/// Encrypt `plaintext` under a fresh nonce, and prepend the nonce to the result.
+fn seal(cipher: &XChaCha20Poly1305, aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>> {
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let payload = Payload {
+        msg: plaintext,
+        aad,
+    };
+    let ciphertext = cipher
+        .encrypt(&nonce, payload)
+        .map_err(|_| Error::io(std::io::ErrorKind::Other, "Encryption failed"))?;
+    Ok([nonce.as_slice(), &ciphertext].concat())
+}
+
+fn cipher(hex_key: &str) -> Result<XChaCha20Poly1305> {
+    let invalid = || Error::InvalidConfig("Encryption keys must be 64 hex digits".to_string());
+    let hex_key = hex_key.trim();
+    if hex_key.len() != 64 || !hex_key.is_ascii() {
+        return Err(invalid());
+    }
+    let key = (0..hex_key.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex_key[i..i + 2], 16).map_err(|_| invalid()))
+        .collect::<Result<Vec<u8>>>()?;
+    XChaCha20Poly1305::new_from_slice(&key).map_err(|_| invalid())
+}
+
+fn read_key_file(path: &Path) -> Result<String> {
+    std::fs::read_to_string(path).map_err(|e| {
+        Error::InvalidConfig(format!("Can't read encryption key file {:?}: {}", path, e))
+    })
+}
@@ -2,100 +2,223 @@ use std::collections::BTreeMap;
 use std::ffi::{OsStr, OsString};
 use std::fmt::Debug;
 use std::fs::File;
-use std::io::{Read, Seek, SeekFrom, Write};
-use std::path::PathBuf;
-use std::sync::Arc;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
 use std::time::{SystemTime, UNIX_EPOCH};
 
-use log::debug;
+use bytes::Bytes;
+use log::{debug, info, warn};
 use memmap2::{Mmap, MmapOptions};
 
-use crate::config::StoreConfig;
-use crate::keydir::Item;
+use crate::config::{StoreConfig, SyncMode};
+use crate::error::Error;
+use crate::keydir::{Item, KeyDir};
+use crate::log::crypt::Keyring;
+use crate::log::format::{FileHeader, Format, FILE_HEADER_SZ};
 use crate::log::read::LogReaderItem;
-use crate::log::LogEntry;
+use crate::log::{flags, now, read_size, read_ts, LogEntry, CRC};
 use crate::Result;
 
+/// The fields preceding an entry's key and value on disk.
+pub(crate) struct EntryHeader {
+    crc: u32,
+    ts: u128,
+    flags: u8,
+    expiry: Option<u128>,
+    key_sz: u64,
+    val_sz: u64,
+    /// How many bytes the header itself took up, which depends on the format.
+    header_sz: u64,
+}
+
+impl EntryHeader {
+    /// Size of the whole entry, header included.
+    fn entry_sz(&self) -> u64 {
+        self.header_sz
+            .saturating_add(self.key_sz)
+            .saturating_add(self.val_sz)
+    }
+}
+
+/// How many of a log file's entries, and how many of its bytes, the `KeyDir` still points at.
+/// Entry sizes are worked out from the sizes of their keys and values, so they're slightly off
+/// for encrypted entries.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct FileStats {
+    pub live_keys: u64,
+    pub live_bytes: u64,
+    /// Entries that were overwritten or deleted since, along with the deletes themselves.
+    pub dead_keys: u64,
+    pub dead_bytes: u64,
+}
+
+impl FileStats {
+    /// Share of the file's entries that's dead, as a percentage of their bytes.
+    pub fn fragmentation(&self) -> u64 {
+        match self.live_bytes + self.dead_bytes {
+            0 => 0,
+            total_bytes => self.dead_bytes * 100 / total_bytes,
+        }
+    }
+
+    /// Move an entry of `entry_sz` bytes over from live to dead.
+    fn kill(&mut self, entry_sz: u64) {
+        self.live_keys = self.live_keys.saturating_sub(1);
+        self.live_bytes = self.live_bytes.saturating_sub(entry_sz);
+        self.dead_keys += 1;
+        self.dead_bytes += entry_sz;
+    }
+
+    /// Move an entry of `entry_sz` bytes over from dead to live.
+    fn revive(&mut self, entry_sz: u64) {
+        self.dead_keys = self.dead_keys.saturating_sub(1);
+        self.dead_bytes = self.dead_bytes.saturating_sub(entry_sz);
+        self.live_keys += 1;
+        self.live_bytes += entry_sz;
+    }
+}
+
 #[derive(Debug)]
 pub struct FileHandle {
     writable: bool,
     pub path: PathBuf,
     inner: File,
-    mmap: Option<Mmap>,
+    mmap: Option<Arc<Mmap>>,
     pub offset: u64,
+    /// Where reading stops, if short of the end of the file.
+    visible_len: Option<u64>,
+    format: Format,
+    stats: FileStats,
 }
 
 impl FileHandle {
+    /// Open the file at `path`, positioned at its first entry. Writable files are always new,
+    /// and start out with a `FileHeader` for the current format.
     pub fn new(path: PathBuf, writable: bool) -> Result<Self> {
         let exists = path.exists();
         if writable && exists {
-            return Err(format!("Can't write to existing file: {:?}", path).into());
+            return Err(Error::io(
+                ErrorKind::AlreadyExists,
+                format!("Can't write to existing file: {:?}", path),
+            ));
         }
-        let inner = File::options()
+        let mut inner = File::options()
             .create_new(!exists)
             .read(true)
             .append(writable)
             .open(&path)?;
+        let format = if writable {
+            inner.write_all(&FileHeader::current().serialize())?;
+            Format::CURRENT
+        } else {
+            Self::read_format(&mut inner, &path)?
+        };
+        inner.seek(SeekFrom::Start(format.data_start()))?;
         Ok(Self {
             writable,
             path,
             inner,
             mmap: None,
-            offset: 0,
+            offset: format.data_start(),
+            visible_len: None,
+            format,
+            stats: FileStats::default(),
         })
     }
 
-    /// Return the length of the associated `File`.
+    /// Work out the format of an existing file from its header, if it has one.
+    fn read_format(file: &mut File, path: &Path) -> Result<Format> {
+        let mut header = [0u8; FILE_HEADER_SZ as usize];
+        if file.metadata()?.len() < FILE_HEADER_SZ {
+            // Either freshly created, or torn before its header made it out. Either way, there
+            // are no entries to read.
+            return Ok(Format::CURRENT);
+        }
+        file.read_exact(&mut header)?;
+        Ok(FileHeader::parse(&header, path)?.map_or(Format::Legacy, |header| header.format))
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    pub fn stats(&self) -> FileStats {
+        self.stats
+    }
+
+    /// Return the length of the associated `File`, or as much of it as is visible.
     #[allow(clippy::len_without_is_empty)]
-    pub fn len(&self) -> u64 {
-        self.inner.metadata().unwrap().len()
+    pub fn len(&self) -> Result<u64> {
+        let len = self.inner.metadata()?.len();
+        Ok(self
+            .visible_len
+            .map_or(len, |visible_len| std::cmp::min(len, visible_len)))
     }
 
-    /// Memory-maps the associated `File`.
-    pub fn memory_map(&mut self, max_log_file_size: u64) {
-        let len = if self.writable {
-            // TODO this is particularly dicey with writes whose values exceed the
-            // `max_log_file_size`. Need safeguards around that.
-            max_log_file_size
-        } else {
-            self.len()
-        };
-        let mmap = unsafe {
-            MmapOptions::new()
-                .len(len as usize)
-                .map(&self.inner)
-                .expect("failed to map the file")
-        };
-        self.mmap = Some(mmap);
+    /// Hide everything past `len`, such as a write still in progress in another process.
+    pub fn set_visible_len(&mut self, len: u64) {
+        self.visible_len = Some(len);
     }
 
-    pub fn is_at_end(&self) -> bool {
-        self.offset >= self.len()
+    /// Memory-maps the associated `File`, unless it's still being written to. A mapping of a
+    /// growing file would either miss later writes or run past its end, so those are read
+    /// through the `File` until `close_for_write`.
+    pub fn memory_map(&mut self) -> Result<()> {
+        if self.writable {
+            return Ok(());
+        }
+        let len = self.len()?;
+        // Can't map an empty file, so leave it to be read through the `File`.
+        if len == 0 {
+            return Ok(());
+        }
+        let mmap = unsafe { MmapOptions::new().len(len as usize).map(&self.inner)? };
+        self.mmap = Some(Arc::new(mmap));
+        Ok(())
     }
 
-    pub fn read_entry(&mut self, start: usize) -> Result<LogEntry> {
-        self.seek(SeekFrom::Start(start as u64))?;
-        let mut metadata = [0u8; 4 + 16 + 2 * 8];
-        self.read_exact(&mut metadata)?;
-        let crc = u32::from_ne_bytes(metadata[0..4].try_into().unwrap());
-        let ts = u128::from_ne_bytes(metadata[4..20].try_into().unwrap());
-        let key_sz = u64::from_ne_bytes(metadata[20..28].try_into().unwrap());
-        let val_sz = u64::from_ne_bytes(metadata[28..36].try_into().unwrap());
-        let mut key = vec![0u8; key_sz as usize];
-        self.read_exact(&mut key)?;
-        let mut val = vec![0u8; val_sz as usize];
-        self.read_exact(&mut val)?;
-        let entry = LogEntry { key, val, ts };
-        if entry.crc() != crc {
-            // TODO should `Err` here!
-            debug!("TODO mismatched CRC!");
-        };
-        Ok(entry)
+    pub fn is_at_end(&self) -> Result<bool> {
+        Ok(self.offset >= self.len()?)
     }
 
-    pub fn read_item(&mut self, item: &Item) -> Result<Vec<u8>> {
-        let entry = self.read_entry(item.val_pos as usize)?;
-        Ok(entry.val)
+    /// Find where a write torn by a crash starts at the end of the file, if there is one:
+    /// either an entry running past the end of the file, or a final entry failing its CRC.
+    pub fn find_torn_tail(&mut self) -> Result<Option<u64>> {
+        let torn_tail = self.scan_for_torn_tail();
+        self.seek(SeekFrom::Start(self.format.data_start()))?;
+        torn_tail
+    }
+
+    fn scan_for_torn_tail(&mut self) -> Result<Option<u64>> {
+        let len = self.len()?;
+        let mut start = self.format.data_start();
+        while start < len {
+            let end = match self.read_entry_header(start as usize) {
+                Ok(header) => start.saturating_add(header.entry_sz()),
+                Err(Error::TruncatedEntry { .. }) => return Ok(Some(start)),
+                Err(e) => return Err(e),
+            };
+            if end > len {
+                return Ok(Some(start));
+            }
+            if end == len && !self.read_raw_entry(start as usize)?.1 {
+                return Ok(Some(start));
+            }
+            start = end;
+        }
+        Ok(None)
+    }
+
+    /// Open the file up to readers on other threads.
+    pub fn share(&self) -> Result<LogFile> {
+        Ok(LogFile {
+            path: self.path.clone(),
+            file: self.inner.try_clone()?,
+            mmap: self.mmap.clone(),
+            visible_len: self.visible_len,
+            format: self.format,
+        })
     }
 
     pub fn try_clone(&self) -> Result<Self> {
@@ -104,24 +227,35 @@ impl FileHandle {
             path: self.path.clone(),
             inner: self.inner.try_clone()?,
             mmap: None,
-            offset: 0,
+            offset: self.format.data_start(),
+            visible_len: self.visible_len,
+            format: self.format,
+            stats: self.stats,
         })
     }
 
+    /// Flush the file's data all the way to disk.
+    pub fn sync(&self) -> Result<()> {
+        Ok(self.inner.sync_data()?)
+    }
+
     pub fn close_for_write(handle: Self) -> Result<Self> {
         // TODO this is pretty clunky, make it prettier
-        Self::new(handle.path, false)
+        Ok(Self {
+            stats: handle.stats,
+            ..Self::new(handle.path, false)?
+        })
     }
 
-    pub fn get_hint_file(&self, writable: bool) -> Option<Self> {
+    pub fn get_hint_file(&self, writable: bool) -> Result<Option<Self>> {
         // TODO if self extension is hint, return None?
         let mut hint_path = self.path.clone();
         hint_path.set_extension("hint");
         if !(hint_path.exists() || writable) {
-            return None;
+            return Ok(None);
         }
 
-        Self::new(hint_path, writable).ok()
+        Self::new(hint_path, writable).map(Some)
     }
 }
 
@@ -129,7 +263,7 @@ impl Read for FileHandle {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
         let bytes_written = match self.mmap.as_ref() {
             Some(mmap) => {
-                let start = self.offset as usize;
+                let start = std::cmp::min(self.offset as usize, mmap.len());
                 let end = std::cmp::min(start + buf.len(), mmap.len());
                 // TODO not sure we always want to copy to the start of buf... maybe
                 buf[..(end - start)].copy_from_slice(&mmap[start..end]);
@@ -146,7 +280,8 @@ impl Write for FileHandle {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
         // TODO `Err` when not `writable`
         let bytes_written = self.inner.write(buf)?;
-        self.offset += bytes_written as u64;
+        // Reads may have moved the offset elsewhere, but appends always land at the end.
+        self.offset = self.inner.stream_position()?;
         Ok(bytes_written)
     }
 
@@ -177,11 +312,22 @@ impl Iterator for FileHandle {
     type Item = Result<LogReaderItem>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.is_at_end() {
-            return None;
+        match self.is_at_end() {
+            Ok(true) => return None,
+            Ok(false) => {}
+            Err(e) => return Some(Err(e)),
         }
         let val_pos = self.offset;
-        let entry = self.read_entry(val_pos as usize).unwrap();
+        let entry = match self.read_entry(val_pos as usize) {
+            Ok(entry) => entry,
+            // The entry was read in full, so the next one can still be found.
+            Err(e @ Error::CrcMismatch { .. }) => return Some(Err(e)),
+            Err(e) => {
+                // Can't tell where the next entry starts, so stop here.
+                self.offset = u64::MAX;
+                return Some(Err(e));
+            }
+        };
         debug!("Read: {}", entry);
 
         Some(Ok(LogReaderItem {
@@ -192,6 +338,335 @@ impl Iterator for FileHandle {
     }
 }
 
+/// Reads the entries of a log file at arbitrary positions. A `FileHandle` reads through its own
+/// offset, whereas a `LogFile` reads positionally, so that threads can share it.
+pub(crate) trait ReadEntry: Read + Seek + Sized {
+    fn path(&self) -> &Path;
+
+    fn format(&self) -> Format;
+
+    /// Length of the file, or as much of it as is visible.
+    fn len(&self) -> Result<u64>;
+
+    /// Fill `buf` from the current offset, reporting a short read as a truncated entry
+    /// starting at `start`.
+    fn read_entry_part(&mut self, buf: &mut [u8], start: usize) -> Result<()> {
+        self.read_exact(buf)
+            .map_err(|e| self.entry_read_error(e, start))
+    }
+
+    fn entry_read_error(&self, e: std::io::Error, start: usize) -> Error {
+        match e.kind() {
+            ErrorKind::UnexpectedEof => Error::TruncatedEntry {
+                path: self.path().to_path_buf(),
+                offset: start as u64,
+            },
+            _ => e.into(),
+        }
+    }
+
+    /// Read the header of the entry at `start`, leaving the offset at its key.
+    fn read_entry_header(&mut self, start: usize) -> Result<EntryHeader> {
+        self.seek(SeekFrom::Start(start as u64))?;
+        let format = self.format();
+        let mut crc = [0u8; 4];
+        self.read_entry_part(&mut crc, start)?;
+        let fields = read_ts(format, self).and_then(|(ts, flags, expiry)| {
+            let key_sz = read_size(format, self)?;
+            let val_sz = read_size(format, self)?;
+            Ok((ts, flags, expiry, key_sz, val_sz))
+        });
+        let (ts, flags, expiry, key_sz, val_sz) =
+            fields.map_err(|e| self.entry_read_error(e, start))?;
+        Ok(EntryHeader {
+            crc: format.decode_u32(crc),
+            ts,
+            flags,
+            expiry,
+            key_sz,
+            val_sz,
+            header_sz: self.stream_position()? - start as u64,
+        })
+    }
+
+    /// Read the entry at `start`, along with whether it matches its CRC.
+    fn read_raw_entry(&mut self, start: usize) -> Result<(LogEntry, bool)> {
+        let header = self.read_entry_header(start)?;
+        // Don't trust the sizes with an allocation before knowing they fit in the file.
+        if start as u64 + header.entry_sz() > self.len()? {
+            return Err(Error::TruncatedEntry {
+                path: self.path().to_path_buf(),
+                offset: start as u64,
+            });
+        }
+        let mut key = vec![0u8; header.key_sz as usize];
+        self.read_entry_part(&mut key, start)?;
+        let mut val = vec![0u8; header.val_sz as usize];
+        self.read_entry_part(&mut val, start)?;
+        let mut entry = LogEntry {
+            key,
+            val,
+            ts: header.ts,
+            flags: header.flags,
+            expiry: header.expiry,
+        };
+        let crc_ok = entry.crc_as(self.format()) == header.crc;
+        // Legacy files hold batch counts in native byte order, unlike the rest, so bring them
+        // in line once checked.
+        if self.format() == Format::Legacy && entry.flags & flags::BATCH_COMMIT != 0 {
+            if let Ok(count) = <[u8; 8]>::try_from(entry.val.as_slice()) {
+                entry.val = Format::Legacy.decode_u64(count).to_le_bytes().to_vec();
+            }
+        }
+        Ok((entry, crc_ok))
+    }
+
+    fn read_entry(&mut self, start: usize) -> Result<LogEntry> {
+        let (entry, crc_ok) = self.read_raw_entry(start)?;
+        if !crc_ok {
+            return Err(Error::CrcMismatch {
+                path: self.path().to_path_buf(),
+                offset: start as u64,
+            });
+        };
+        Ok(entry)
+    }
+}
+
+impl ReadEntry for FileHandle {
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn format(&self) -> Format {
+        self.format
+    }
+
+    fn len(&self) -> Result<u64> {
+        FileHandle::len(self)
+    }
+}
+
+/// A log file opened for reading by any number of threads at once.
+#[derive(Debug)]
+pub struct LogFile {
+    path: PathBuf,
+    file: File,
+    mmap: Option<Arc<Mmap>>,
+    visible_len: Option<u64>,
+    format: Format,
+}
+
+impl LogFile {
+    pub fn read_entry(&self, start: u64) -> Result<LogEntry> {
+        let mut cursor = LogCursor {
+            file: self,
+            offset: start,
+        };
+        match self.mmap {
+            Some(_) => cursor.read_entry(start as usize),
+            // Headers get read a few bytes at a time, which would each take a syscall.
+            None => BufReader::new(cursor).read_entry(start as usize),
+        }
+    }
+
+    /// Read the value of the entry at `start` straight out of the file's mmap, or return `None`
+    /// if it isn't mapped or the value isn't stored as is.
+    fn read_mapped_val(&self, start: u64) -> Result<Option<Bytes>> {
+        let Some(mmap) = &self.mmap else {
+            return Ok(None);
+        };
+        let mut cursor = LogCursor {
+            file: self,
+            offset: start,
+        };
+        let header = cursor.read_entry_header(start as usize)?;
+        if header.flags & (flags::ENCRYPTED | flags::LZ4 | flags::ZSTD) != 0 {
+            return Ok(None);
+        }
+        let end = start.saturating_add(header.entry_sz());
+        if end > self.len()? {
+            return Err(Error::TruncatedEntry {
+                path: self.path.clone(),
+                offset: start,
+            });
+        }
+        // Everything after the CRC itself is what it covers, exactly as it's laid out on disk.
+        let crc_sz = std::mem::size_of_val(&header.crc);
+        let (start, end) = (start as usize, end as usize);
+        if CRC.checksum(&mmap[start + crc_sz..end]) != header.crc {
+            return Err(Error::CrcMismatch {
+                path: self.path.clone(),
+                offset: start as u64,
+            });
+        }
+        let val_start = end - header.val_sz as usize;
+        Ok(Some(
+            Bytes::from_owner(SharedMmap(mmap.clone())).slice(val_start..end),
+        ))
+    }
+
+    fn len(&self) -> std::io::Result<u64> {
+        let len = match &self.mmap {
+            Some(mmap) => mmap.len() as u64,
+            None => self.file.metadata()?.len(),
+        };
+        Ok(self
+            .visible_len
+            .map_or(len, |visible_len| std::cmp::min(len, visible_len)))
+    }
+}
+
+/// Lets `Bytes` keep the mmap they point into alive.
+struct SharedMmap(Arc<Mmap>);
+
+impl AsRef<[u8]> for SharedMmap {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// Reads a `LogFile` from an offset of its own, without moving the file's.
+struct LogCursor<'a> {
+    file: &'a LogFile,
+    offset: u64,
+}
+
+impl Read for LogCursor<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let bytes_read = match &self.file.mmap {
+            Some(mmap) => {
+                let start = std::cmp::min(self.offset as usize, mmap.len());
+                let end = std::cmp::min(start + buf.len(), mmap.len());
+                buf[..(end - start)].copy_from_slice(&mmap[start..end]);
+                end - start
+            }
+            None => read_at(&self.file.file, buf, self.offset)?,
+        };
+        self.offset += bytes_read as u64;
+        Ok(bytes_read)
+    }
+}
+
+impl Seek for LogCursor<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let offset = match pos {
+            SeekFrom::Start(k) => Some(k),
+            SeekFrom::Current(k) => self.offset.checked_add_signed(k),
+            SeekFrom::End(k) => self.file.len()?.checked_add_signed(k),
+        };
+        self.offset = offset
+            .ok_or_else(|| std::io::Error::new(ErrorKind::InvalidInput, "Seek out of bounds"))?;
+        Ok(self.offset)
+    }
+}
+
+impl ReadEntry for LogCursor<'_> {
+    fn path(&self) -> &Path {
+        &self.file.path
+    }
+
+    fn format(&self) -> Format {
+        self.file.format
+    }
+
+    fn len(&self) -> Result<u64> {
+        Ok(self.file.len()?)
+    }
+}
+
+impl ReadEntry for BufReader<LogCursor<'_>> {
+    fn path(&self) -> &Path {
+        self.get_ref().path()
+    }
+
+    fn format(&self) -> Format {
+        self.get_ref().format()
+    }
+
+    fn len(&self) -> Result<u64> {
+        self.get_ref().len()
+    }
+}
+
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_read(file, buf, offset)
+}
+
+/// The log files as readers see them, kept up to date by the `FileManager`. Readers only hold
+/// its lock for long enough to look a file up, so they neither wait on writes nor on each
+/// other.
+#[derive(Debug, Default)]
+pub struct LogFiles {
+    files: RwLock<BTreeMap<PathBuf, Arc<LogFile>>>,
+    keyring: Arc<Keyring>,
+}
+
+impl LogFiles {
+    fn new(keyring: Arc<Keyring>) -> Self {
+        Self {
+            files: RwLock::default(),
+            keyring,
+        }
+    }
+
+    fn insert(&self, file: LogFile) {
+        self.files
+            .write()
+            .unwrap()
+            .insert(file.path.clone(), Arc::new(file));
+    }
+
+    fn remove(&self, path: &Path) {
+        self.files.write().unwrap().remove(path);
+    }
+
+    /// Read the value `item` points at, the way it was written.
+    pub fn read_item(&self, item: &Item) -> Result<Vec<u8>> {
+        let file = self.get(&item.path)?;
+        self.decode_item(&file, item)
+    }
+
+    /// Like `read_item`, but point into the file's mmap rather than copying the value out of
+    /// it, whenever the file is mapped and the value stored as is.
+    pub fn read_item_bytes(&self, item: &Item) -> Result<Bytes> {
+        let file = self.get(&item.path)?;
+        match file.read_mapped_val(item.val_pos)? {
+            Some(val) => Ok(val),
+            None => Ok(self.decode_item(&file, item)?.into()),
+        }
+    }
+
+    fn decode_item(&self, file: &LogFile, item: &Item) -> Result<Vec<u8>> {
+        let entry = file.read_entry(item.val_pos)?;
+        let entry = self.keyring.decrypt(entry, &item.path, item.val_pos)?;
+        Ok(entry.decompress()?.val)
+    }
+
+    fn get(&self, path: &Path) -> Result<Arc<LogFile>> {
+        self.files
+            .read()
+            .unwrap()
+            .get(path)
+            .cloned()
+            .ok_or_else(|| {
+                Error::io(
+                    ErrorKind::NotFound,
+                    format!("No log file found for id: {:?}", path),
+                )
+            })
+    }
+}
+
+/// Subdirectory of the log directory that damaged files get moved into.
+pub const QUARANTINE_DIR: &str = "quarantine";
+
 #[derive(Debug, Default)]
 pub struct FileManager {
     config: Arc<StoreConfig>,
@@ -200,15 +675,151 @@ pub struct FileManager {
     pub current: Option<PathBuf>,
     // TODO only temporarily `pub`!
     pub inner: BTreeMap<PathBuf, FileHandle>,
+    last_ts: u128,
+    /// Whether the current file has writes that haven't been synced yet.
+    dirty: bool,
+    /// Files rotated out with writes that haven't been synced yet, under `SyncMode::Os`.
+    unsynced: Vec<PathBuf>,
+    /// Whether files were created in the log directory since it was last synced.
+    dir_dirty: bool,
+    /// Hint file of the log file the last hint was written for, along with that log file.
+    hint_writer: Option<(PathBuf, BufWriter<File>)>,
+    read_only: bool,
+    keyring: Arc<Keyring>,
+    log_files: Arc<LogFiles>,
 }
 
 impl FileManager {
-    pub fn new(config: Arc<StoreConfig>) -> Self {
-        Self {
+    pub fn new(config: Arc<StoreConfig>) -> Result<Self> {
+        let keyring = Arc::new(Keyring::from_config(&config)?);
+        Ok(Self {
+            log_files: Arc::new(LogFiles::new(keyring.clone())),
+            keyring,
             config,
             current: None,
             inner: BTreeMap::default(),
+            last_ts: 0,
+            dirty: false,
+            unsynced: Vec::new(),
+            dir_dirty: false,
+            hint_writer: None,
+            read_only: false,
+        })
+    }
+
+    /// A `FileManager` that never modifies the log directory, for reading alongside a writer.
+    pub fn read_only(config: Arc<StoreConfig>) -> Result<Self> {
+        Ok(Self {
+            read_only: true,
+            ..Self::new(config)?
+        })
+    }
+
+    pub(crate) fn keyring(&self) -> Arc<Keyring> {
+        self.keyring.clone()
+    }
+
+    /// The files for readers to get values from, without going through this `FileManager`.
+    pub fn log_files(&self) -> Arc<LogFiles> {
+        self.log_files.clone()
+    }
+
+    /// Make readers of `log_files` see this `FileManager`'s files, and keep them up to date
+    /// from now on, as when it takes over from another one.
+    pub fn publish_to(&mut self, log_files: &Arc<LogFiles>) {
+        let files = std::mem::take(&mut *self.log_files.files.write().unwrap());
+        *log_files.files.write().unwrap() = files;
+        self.log_files = log_files.clone();
+    }
+
+    /// Turn an unencrypted entry into what gets written given the current settings: compressed,
+    /// then encrypted.
+    pub(crate) fn encode(&self, entry: LogEntry) -> Result<LogEntry> {
+        let entry = entry.compress(self.config.compression, self.config.compression_min_size)?;
+        self.keyring.encrypt(entry)
+    }
+
+    /// Hand out a timestamp for a new write, strictly greater than any handed out or seen so
+    /// far, so that it can double as the entry's `Version`.
+    pub fn next_ts(&mut self) -> Result<u128> {
+        self.last_ts = std::cmp::max(now()?, self.last_ts + 1);
+        Ok(self.last_ts)
+    }
+
+    /// Make sure future timestamps come after `ts`.
+    pub fn observe_ts(&mut self, ts: u128) {
+        self.last_ts = std::cmp::max(self.last_ts, ts);
+    }
+
+    /// Truncate torn writes left at the end of log files by a crash.
+    ///
+    /// Only a file that was being appended to can have a torn tail. That's usually the newest
+    /// one, but files written by a `merge` are named after the moment they were created, so
+    /// check every file that doesn't have a hint file yet.
+    pub fn truncate_torn_tails(&self) -> Result<()> {
+        for entry in std::fs::read_dir(&self.config.log_dir)?.flatten() {
+            let path = entry.path();
+            if path.extension() != Some(OsStr::new("cask")) || path.with_extension("hint").exists()
+            {
+                continue;
+            }
+            let mut handle = FileHandle::new(path.clone(), false)?;
+            if let Some(offset) = handle.find_torn_tail()? {
+                warn!(
+                    "Truncating torn write at the end of {:?}: dropping {} bytes from offset {}",
+                    path,
+                    handle.len()? - offset,
+                    offset
+                );
+                let file = File::options().write(true).open(&path)?;
+                file.set_len(offset)?;
+                file.sync_all()?;
+            }
         }
+        Ok(())
+    }
+
+    /// Rewrite files in formats older than `Format::CURRENT`, hint files included. Files that
+    /// can't be read in full are left as they are, for the `CorruptionPolicy` to deal with
+    /// while loading.
+    pub fn upgrade_files(&self) -> Result<()> {
+        // An upgrade cut short leaves its new files behind. Until they're renamed into place
+        // the old log file is whole, and after that the new one reads fine without a hint file,
+        // so they can go either way.
+        for entry in std::fs::read_dir(&self.config.log_dir)?.flatten() {
+            let path = entry.path();
+            if path.extension() == Some(OsStr::new("upgrade")) {
+                warn!("Removing {:?} left over from an interrupted upgrade", path);
+                std::fs::remove_file(&path)?;
+            }
+        }
+        for entry in std::fs::read_dir(&self.config.log_dir)?.flatten() {
+            let path = entry.path();
+            if path.extension() != Some(OsStr::new("cask")) {
+                continue;
+            }
+            let handle = FileHandle::new(path.clone(), false)?;
+            let format = handle.format();
+            if format == Format::CURRENT {
+                continue;
+            }
+            match handle
+                .map(|read| read.map(|read| read.entry))
+                .collect::<Result<Vec<_>>>()
+            {
+                Ok(entries) => {
+                    info!(
+                        "Upgrading {:?} from {:?} to {:?}",
+                        path,
+                        format,
+                        Format::CURRENT
+                    );
+                    upgrade_file(&path, entries)?;
+                }
+                Err(e) => warn!("Leaving {:?} in {:?}: {}", path, format, e),
+            }
+        }
+        Ok(())
     }
 
     pub fn initialize_from_log_dir(&mut self) -> Result<()> {
@@ -217,13 +828,46 @@ impl FileManager {
             .filter(|dir_entry| dir_entry.path().extension() == Some(OsStr::new("cask")))
         {
             let path = entry.path();
-            let mut handle = FileHandle::new(path, false).unwrap();
-            handle.memory_map(self.config.max_log_file_size);
-            self.insert(handle);
+            let mut handle = FileHandle::new(path, false)?;
+            if self.read_only && !handle.path.with_extension("hint").exists() {
+                // A torn tail might just be a write in progress, so leave it be but don't read it.
+                if let Some(len) = handle.find_torn_tail()? {
+                    debug!(
+                        "Reading {:?} up to incomplete entry at {}",
+                        handle.path, len
+                    );
+                    handle.set_visible_len(len);
+                }
+            }
+            handle.memory_map()?;
+            self.insert(handle)?;
         }
         Ok(())
     }
 
+    pub fn config(&self) -> &StoreConfig {
+        &self.config
+    }
+
+    /// Move a damaged log file, along with its hint file, into `quarantine/` in the log
+    /// directory, and stop serving reads from it.
+    pub fn quarantine(&mut self, path: &Path) -> Result<()> {
+        self.remove(path);
+        if self.read_only {
+            warn!("Ignoring {:?}, which would be quarantined", path);
+            return Ok(());
+        }
+        let quarantine_dir = self.config.log_dir.join(QUARANTINE_DIR);
+        std::fs::create_dir_all(&quarantine_dir)?;
+        for path in [path.to_path_buf(), path.with_extension("hint")] {
+            if let (true, Some(file_name)) = (path.exists(), path.file_name()) {
+                std::fs::rename(&path, quarantine_dir.join(file_name))?;
+            }
+        }
+        warn!("Quarantined {:?} into {:?}", path, quarantine_dir);
+        Ok(())
+    }
+
     pub fn iter(&self) -> impl Iterator<Item = &FileHandle> {
         self.inner.values()
     }
@@ -237,108 +881,356 @@ impl FileManager {
     }
 
     pub fn get_current_mut(&mut self) -> Result<&mut FileHandle> {
-        // TODO might not have it open! handle properly with an `Error` type.
-        let current = self.current.as_ref().unwrap();
+        let current = self
+            .current
+            .as_ref()
+            .ok_or_else(|| Error::io(ErrorKind::NotFound, "No log file open for write"))?;
         // TODO hate copypasta here but not sure how to call `self.get_mut` without running afoul
         // of borrow checker...
-        self.inner
-            .get_mut(current)
-            .ok_or_else(|| format!("No log file found for id: {:?}", current).into())
+        self.inner.get_mut(current).ok_or_else(|| {
+            Error::io(
+                ErrorKind::NotFound,
+                format!("No log file found for id: {:?}", current),
+            )
+        })
     }
 
     pub fn get_mut(&mut self, id: &PathBuf) -> crate::Result<&mut FileHandle> {
-        self.inner
-            .get_mut(id)
-            .ok_or_else(|| format!("No log file found for id: {:?}", id).into())
+        self.inner.get_mut(id).ok_or_else(|| {
+            Error::io(
+                ErrorKind::NotFound,
+                format!("No log file found for id: {:?}", id),
+            )
+        })
     }
 
-    pub fn insert(&mut self, handle: FileHandle) {
+    pub fn insert(&mut self, handle: FileHandle) -> Result<()> {
+        self.log_files.insert(handle.share()?);
         self.inner.insert(handle.path.clone(), handle);
+        Ok(())
     }
 
-    pub fn remove(&mut self, path: &PathBuf) -> Option<FileHandle> {
+    pub fn remove(&mut self, path: &Path) -> Option<FileHandle> {
+        self.log_files.remove(path);
         self.inner.remove(path)
     }
 
-    fn new_file_name(&self) -> OsString {
+    /// Count a freshly written entry toward its file, as live unless it's a delete.
+    pub(crate) fn count_written(&mut self, item: &Item, live: bool) {
+        if let Some(handle) = self.inner.get_mut(&item.path) {
+            let entry_sz = item.entry_sz(handle.format);
+            if live {
+                handle.stats.live_keys += 1;
+                handle.stats.live_bytes += entry_sz;
+            } else {
+                handle.stats.dead_keys += 1;
+                handle.stats.dead_bytes += entry_sz;
+            }
+        }
+    }
+
+    /// Count the entry `item` points at as dead, now that its key was overwritten or deleted.
+    pub(crate) fn count_superseded(&mut self, item: &Item) {
+        if let Some(handle) = self.inner.get_mut(&item.path) {
+            handle.stats.kill(item.entry_sz(handle.format));
+        }
+    }
+
+    /// Start the counts for the files in `entries` over, holding the number of entries in each,
+    /// from what `keydir` points at. Everything else in the files is dead.
+    pub(crate) fn recount(
+        &mut self,
+        keydir: &KeyDir,
+        entries: &BTreeMap<PathBuf, u64>,
+    ) -> Result<()> {
+        for (path, &count) in entries {
+            if let Some(handle) = self.inner.get_mut(path) {
+                handle.stats = FileStats {
+                    dead_keys: count,
+                    dead_bytes: handle.len()?.saturating_sub(handle.format.data_start()),
+                    ..Default::default()
+                };
+            }
+        }
+        for item in keydir.data.values() {
+            if !entries.contains_key(&item.path) {
+                continue;
+            }
+            if let Some(handle) = self.inner.get_mut(&item.path) {
+                handle.stats.revive(item.entry_sz(handle.format));
+            }
+        }
+        Ok(())
+    }
+
+    fn new_file_name(&self) -> Result<OsString> {
         // Maybe you'd want to call the merge files something different, but OK for now.
         SystemTime::now()
             .duration_since(UNIX_EPOCH)
-            .ok()
-            .map(|d| format!("{}.cask", d.as_micros()))
-            .unwrap()
-            .into()
+            .map(|d| format!("{}.cask", d.as_micros()).into())
+            .map_err(|e| Error::io(ErrorKind::Other, e.to_string()))
     }
 
-    fn rotate(&mut self) -> Result<()> {
-        // Close current for write
+    /// Stop writing to the current file, leaving it to be merged like any other closed file.
+    pub fn close_current(&mut self) -> Result<()> {
         if let Some(current) = self.current.take() {
             let old = self.inner.remove(&current);
             if let Some(old) = old {
                 let mut read_handle = FileHandle::close_for_write(old)?;
-                read_handle.memory_map(self.config.max_log_file_size);
-                self.insert(read_handle);
+                read_handle.memory_map()?;
+                self.insert(read_handle)?;
             }
         }
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        // The current file won't be around to sync later, so either sync it now or keep track
+        // of it for the next explicit `sync`.
+        if self.config.sync_mode != SyncMode::Os {
+            self.sync()?;
+        } else if self.dirty {
+            self.unsynced.extend(self.current.clone());
+            self.dirty = false;
+        }
+        self.close_current()?;
 
-        // TODO should hit this with an `ok_or`?
-        let file_name = self.new_file_name();
+        let file_name = self.new_file_name()?;
         let path = self.config.log_dir.join(file_name);
         debug!("Opening new write file {:?}", path);
         let mut write_handle = FileHandle::new(path.clone(), true)?;
-        write_handle.memory_map(self.config.max_log_file_size);
-        self.insert(write_handle);
+        write_handle.memory_map()?;
+        self.insert(write_handle)?;
         self.current = Some(path);
+        self.dir_dirty = true;
         Ok(())
     }
 
-    pub fn will_fit(&mut self, line: &[u8]) -> Result<bool> {
+    /// Whether `len` more bytes can go in the current file, on top of `buffered` bytes waiting
+    /// to be written to it. Anything bigger than `max_log_file_size` never fits, so it gets a
+    /// file to itself, starting with an empty one.
+    fn will_fit(&mut self, buffered: u64, len: u64) -> Result<bool> {
         let current = self.get_current_mut()?;
-        Ok(line.len() as u64 + current.stream_position()? <= self.config.max_log_file_size)
+        let position = current.stream_position()? + buffered;
+        Ok(position == current.format().data_start()
+            || position + len <= self.config.max_log_file_size)
     }
 
-    pub fn write(&mut self, line: &[u8]) -> Result<(PathBuf, u64)> {
-        if self.current.is_none() || !self.will_fit(line)? {
-            self.rotate()?;
-        };
-        let current = self.get_current_mut()?;
-        current.write_all(line)?;
-        Ok((current.path.clone(), current.stream_position()?))
+    /// Append `buf` to the current file, if there's anything in it.
+    fn flush_buffer(&mut self, buf: &mut Vec<u8>) -> Result<()> {
+        if !buf.is_empty() {
+            self.get_current_mut()?.write_all(buf)?;
+            self.dirty = true;
+            buf.clear();
+        }
+        Ok(())
+    }
+
+    /// Sync every outstanding write, whichever file it went to, along with the log directory
+    /// if files were created in it since.
+    pub fn sync(&mut self) -> Result<()> {
+        for path in std::mem::take(&mut self.unsynced) {
+            // Files merged away since have nothing left to sync.
+            if let Some(handle) = self.inner.get(&path) {
+                handle.sync()?;
+            }
+        }
+        if self.dirty {
+            self.get_current_mut()?.sync()?;
+            self.dirty = false;
+        }
+        if self.dir_dirty {
+            sync_dir(&self.config.log_dir)?;
+            self.dir_dirty = false;
+        }
+        Ok(())
+    }
+
+    /// Sync every file, along with its hint file, regardless of the `SyncMode`.
+    pub fn sync_all(&mut self) -> Result<()> {
+        self.flush_hints()?;
+        for handle in self.iter() {
+            handle.sync()?;
+            if let Some(hint_file) = handle.get_hint_file(false)? {
+                hint_file.sync()?;
+            }
+        }
+        sync_dir(&self.config.log_dir)?;
+        self.unsynced.clear();
+        self.dirty = false;
+        self.dir_dirty = false;
+        Ok(())
     }
 
     pub fn set(&mut self, entry: &LogEntry) -> Result<Item> {
-        let serialized = entry.serialize_with_crc();
-        let line = serialized.as_slice();
-        let (path, position) = self.write(line)?;
-        let val_pos = position - (line.len() as u64);
-        Ok(Item {
-            path,
-            val_sz: entry.val.len(),
-            val_pos,
-            ts: entry.ts,
-        })
+        let mut items = self.set_all(&[std::slice::from_ref(entry)])?;
+        Ok(items.remove(0).remove(0))
     }
 
-    pub fn read_item(&mut self, item: &Item) -> Result<Vec<u8>> {
-        let handle = self.get_mut(&item.path)?;
-        handle.read_item(item)
+    /// Write `entries` as one atomic batch, followed by the marker committing them.
+    pub fn set_batch(&mut self, entries: &[LogEntry]) -> Result<Vec<Item>> {
+        Ok(self.set_all(&[entries])?.remove(0))
     }
 
-    fn get_hint_file_for_current(&self) -> Option<File> {
-        self.current.as_ref().map(|path| {
-            let mut hint_path = path.clone();
-            hint_path.set_extension("hint");
-            File::options()
-                .create_new(!hint_path.exists())
-                .append(true)
-                .open(hint_path)
-                .unwrap() // TODO
-        })
+    /// Write each of `units` in turn: either a lone entry, or the entries of a batch, which get
+    /// followed by their commit marker. As many units as fit in the current file go out in a
+    /// single write, and the `SyncMode` is honored once at the end rather than per unit.
+    pub fn set_all(&mut self, units: &[&[LogEntry]]) -> Result<Vec<Vec<Item>>> {
+        let mut buf = Vec::new();
+        let mut items = Vec::with_capacity(units.len());
+        for entries in units {
+            let mut lines: Vec<_> = entries.iter().map(|e| e.serialize_with_crc()).collect();
+            if let Some(first) = entries.first().filter(|entry| entry.in_batch()) {
+                lines.push(LogEntry::batch_commit(entries.len(), first.ts).serialize_with_crc());
+            }
+            // Units never straddle two files, which batches depend on.
+            let len = lines.iter().map(|line| line.len() as u64).sum();
+            if self.current.is_none() || !self.will_fit(buf.len() as u64, len)? {
+                self.flush_buffer(&mut buf)?;
+                self.rotate()?;
+            }
+            let current = self.get_current_mut()?;
+            // Commit markers are dead weight from the start, and have no key to count.
+            if lines.len() > entries.len() {
+                current.stats.dead_bytes += lines.last().map_or(0, |line| line.len() as u64);
+            }
+            let path = current.path.clone();
+            let mut val_pos = current.stream_position()? + buf.len() as u64;
+            items.push(
+                entries
+                    .iter()
+                    .zip(lines.iter())
+                    .map(|(entry, line)| {
+                        let item = Item {
+                            path: path.clone(),
+                            key_sz: entry.key.len(),
+                            val_sz: entry.val.len(),
+                            val_pos,
+                            ts: entry.ts,
+                            expiry: entry.expiry,
+                        };
+                        val_pos += line.len() as u64;
+                        item
+                    })
+                    .collect(),
+            );
+            buf.extend(lines.concat());
+        }
+        self.flush_buffer(&mut buf)?;
+        if self.config.sync_mode == SyncMode::Always {
+            self.sync()?;
+        }
+        Ok(items)
     }
 
-    pub fn write_hint(&self, hint: &[u8]) -> Result<()> {
-        let mut hint_file = self.get_hint_file_for_current().unwrap();
-        hint_file.write_all(hint)?;
+    fn get_hint_file_for_current(&self) -> Result<File> {
+        let path = self
+            .current
+            .as_ref()
+            .ok_or_else(|| Error::io(ErrorKind::NotFound, "No log file open for write"))?;
+        let mut hint_path = path.clone();
+        hint_path.set_extension("hint");
+        let exists = hint_path.exists();
+        let mut hint_file = File::options()
+            .create_new(!exists)
+            .append(true)
+            .open(hint_path)?;
+        if !exists {
+            hint_file.write_all(&FileHeader::current().serialize())?;
+        }
+        Ok(hint_file)
+    }
+
+    /// Append `hint` to the hint file of the current log file. Hints are buffered until the
+    /// log file changes, or until `flush_hints` or `sync_all`.
+    pub fn write_hint(&mut self, hint: &[u8]) -> Result<()> {
+        if self.hint_writer.as_ref().map(|(path, _)| path) != self.current.as_ref() {
+            self.flush_hints()?;
+        }
+        if self.hint_writer.is_none() {
+            let hint_file = BufWriter::new(self.get_hint_file_for_current()?);
+            self.hint_writer = self.current.clone().map(|path| (path, hint_file));
+        }
+        if let Some((_, writer)) = &mut self.hint_writer {
+            writer.write_all(hint)?;
+        }
         Ok(())
     }
+
+    /// Write out any hints `write_hint` still has buffered.
+    pub fn flush_hints(&mut self) -> Result<()> {
+        if let Some((_, mut writer)) = self.hint_writer.take() {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Replace the file at `path` with `entries` in the current format, regenerating its hint
+/// file if it had one. Deletes are flagged along the way, since the legacy tombstone
+/// value only counts in entries without `flags::EXPLICIT_TOMBSTONES`.
+fn upgrade_file(path: &Path, entries: Vec<LogEntry>) -> Result<()> {
+    let header = FileHeader::current().serialize();
+    let mut cask = header.to_vec();
+    let mut hint = header.to_vec();
+    for mut entry in entries {
+        if entry.flags & flags::EXPLICIT_TOMBSTONES == 0 {
+            if entry.is_tombstone() {
+                entry.val.clear();
+                entry.flags |= flags::TOMBSTONE;
+            }
+            entry.flags |= flags::EXPLICIT_TOMBSTONES;
+        }
+        if entry.batch_commit_count().is_none() {
+            let item = Item {
+                path: path.to_path_buf(),
+                key_sz: entry.key.len(),
+                val_sz: entry.val.len(),
+                val_pos: cask.len() as u64,
+                ts: entry.ts,
+                expiry: entry.expiry,
+            };
+            hint.extend(item.serialize_as_hint(&entry.key, entry.flags));
+        }
+        cask.extend(entry.serialize_with_crc());
+    }
+
+    let hint_path = path.with_extension("hint");
+    let had_hint = hint_path.exists();
+    let upgraded_path = path.with_extension("cask.upgrade");
+    let upgraded_hint_path = path.with_extension("hint.upgrade");
+    write_synced(&upgraded_path, &cask)?;
+    if had_hint {
+        write_synced(&upgraded_hint_path, &hint)?;
+    }
+    // A log file without its hint file reads fine, whatever its format, so drop the old
+    // hint file first and only bring in the new one once the log file is in place.
+    if had_hint {
+        std::fs::remove_file(&hint_path)?;
+    }
+    std::fs::rename(&upgraded_path, path)?;
+    if had_hint {
+        std::fs::rename(&upgraded_hint_path, &hint_path)?;
+    }
+    Ok(())
+}
+
+/// Sync the directory at `dir` itself, so that the files created in it survive a crash along
+/// with their contents. Only Unix lets directories be opened for this.
+fn sync_dir(dir: &Path) -> Result<()> {
+    #[cfg(unix)]
+    File::open(dir)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+fn write_synced(path: &Path, bytes: &[u8]) -> Result<()> {
+    let mut file = File::create(path)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    Ok(())
 }
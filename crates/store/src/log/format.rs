@@ -0,0 +1,165 @@
+use std::io::{self, Read};
+use std::path::Path;
+
+use crate::error::Error;
+use crate::Result;
+
+/// First bytes of every `.cask` and `.hint` file written with a header.
+pub const MAGIC: [u8; 4] = *b"BCSK";
+
+/// Size of the magic number, format version and flags starting each file.
+pub const FILE_HEADER_SZ: u64 = 4 + 2 + 2;
+
+/// How the entries in a file are laid out on disk.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Format {
+    /// No file header, and native-endian fields.
+    Legacy,
+    /// Little-endian fields after a `FileHeader`, with the timestamp, flags and expiry packed
+    /// into a `u128` and `u64` sizes.
+    V1,
+    /// Like `V1`, but with a `u64` timestamp, a flag byte, and varints for the expiry and
+    /// sizes, taking entry headers down from 36 bytes to as few as 16.
+    V2,
+}
+
+impl Format {
+    /// The format all new files are written in. Files in older formats are still read, but
+    /// get rewritten in this one whenever the store is opened for writing.
+    pub const CURRENT: Self = Self::V2;
+
+    pub fn version(self) -> u16 {
+        match self {
+            Self::Legacy => 0,
+            Self::V1 => 1,
+            Self::V2 => 2,
+        }
+    }
+
+    fn from_version(version: u16) -> Option<Self> {
+        match version {
+            1 => Some(Self::V1),
+            2 => Some(Self::V2),
+            _ => None,
+        }
+    }
+
+    /// Offset of the first entry in a file of this format.
+    pub fn data_start(self) -> u64 {
+        match self {
+            Self::Legacy => 0,
+            Self::V1 | Self::V2 => FILE_HEADER_SZ,
+        }
+    }
+
+    pub fn encode_u32(self, n: u32) -> [u8; 4] {
+        match self {
+            Self::Legacy => n.to_ne_bytes(),
+            Self::V1 | Self::V2 => n.to_le_bytes(),
+        }
+    }
+
+    pub fn encode_u64(self, n: u64) -> [u8; 8] {
+        match self {
+            Self::Legacy => n.to_ne_bytes(),
+            Self::V1 | Self::V2 => n.to_le_bytes(),
+        }
+    }
+
+    pub fn encode_u128(self, n: u128) -> [u8; 16] {
+        match self {
+            Self::Legacy => n.to_ne_bytes(),
+            Self::V1 | Self::V2 => n.to_le_bytes(),
+        }
+    }
+
+    pub fn decode_u32(self, bytes: [u8; 4]) -> u32 {
+        match self {
+            Self::Legacy => u32::from_ne_bytes(bytes),
+            Self::V1 | Self::V2 => u32::from_le_bytes(bytes),
+        }
+    }
+
+    pub fn decode_u64(self, bytes: [u8; 8]) -> u64 {
+        match self {
+            Self::Legacy => u64::from_ne_bytes(bytes),
+            Self::V1 | Self::V2 => u64::from_le_bytes(bytes),
+        }
+    }
+
+    pub fn decode_u128(self, bytes: [u8; 16]) -> u128 {
+        match self {
+            Self::Legacy => u128::from_ne_bytes(bytes),
+            Self::V1 | Self::V2 => u128::from_le_bytes(bytes),
+        }
+    }
+}
+
+/// Append `n` to `buf` as a LEB128 varint: seven bits per byte, lowest first, with the top bit
+/// set on every byte but the last.
+pub fn encode_varint(mut n: u64, buf: &mut Vec<u8>) {
+    while n >= 0x80 {
+        buf.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+}
+
+/// Read a varint written by `encode_varint`.
+pub fn read_varint(reader: &mut impl Read) -> io::Result<u64> {
+    let mut n = 0;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0u8; 1];
+        reader.read_exact(&mut byte)?;
+        n |= ((byte[0] & 0x7f) as u64) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(n);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "Varint runs past 64 bits",
+    ))
+}
+
+/// Header at the start of every file since `Format::V1`, always little-endian.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct FileHeader {
+    pub format: Format,
+    /// Reserved for per-file options, currently always 0.
+    pub flags: u16,
+}
+
+impl FileHeader {
+    pub fn current() -> Self {
+        Self {
+            format: Format::CURRENT,
+            flags: 0,
+        }
+    }
+
+    pub fn serialize(&self) -> [u8; FILE_HEADER_SZ as usize] {
+        let mut serialized = [0u8; FILE_HEADER_SZ as usize];
+        serialized[..4].copy_from_slice(&MAGIC);
+        serialized[4..6].copy_from_slice(&self.format.version().to_le_bytes());
+        serialized[6..].copy_from_slice(&self.flags.to_le_bytes());
+        serialized
+    }
+
+    /// Parse the first bytes of the file at `path`, or return `None` if they aren't a header,
+    /// meaning the file predates them.
+    pub fn parse(bytes: &[u8; FILE_HEADER_SZ as usize], path: &Path) -> Result<Option<Self>> {
+        if bytes[..4] != MAGIC {
+            return Ok(None);
+        }
+        let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+        let format = Format::from_version(version).ok_or_else(|| Error::UnsupportedFormat {
+            path: path.to_path_buf(),
+            version,
+        })?;
+        Ok(Some(Self {
+            format,
+            flags: u16::from_le_bytes([bytes[6], bytes[7]]),
+        }))
+    }
+}
@@ -1,26 +1,152 @@
 use std::fmt;
+use std::io::Read;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use crc::{Crc, CRC_32_ISCSI};
 
+use crate::error::Error;
+use crate::log::format::{encode_varint, read_varint, Format};
 use crate::Result;
 
+mod compress;
+pub(crate) mod crypt;
 pub mod files;
+pub mod format;
 pub mod read;
 
 // TODO investigate if this is the correct algorithm
-const CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
+pub(crate) const CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
+
+/// Up to `Format::V1`, timestamps are stored as a `u128`, but microseconds since the epoch fit
+/// comfortably in the low 64 bits, so the high bits carry per-entry metadata: a flag byte, then
+/// the expiry in the remaining 56 bits. They are always zero in entries written before the
+/// metadata existed.
+const FLAGS_SHIFT: u32 = 64;
+const EXPIRY_SHIFT: u32 = 72;
+/// Latest expiry that fits in the packed timestamp, 2^56 microseconds after the epoch, in the
+/// year 4253.
+pub(crate) const MAX_EXPIRY: u128 = (1 << (128 - EXPIRY_SHIFT)) - 1;
+
+/// Bits of the flag byte stored alongside each entry's timestamp.
+pub mod flags {
+    /// The entry belongs to a `WriteBatch` and only counts once the batch's commit is read.
+    pub const IN_BATCH: u8 = 1;
+    /// The entry commits the `IN_BATCH` entries directly preceding it.
+    pub const BATCH_COMMIT: u8 = 1 << 1;
+    /// The entry deletes its key, and carries no value.
+    pub const TOMBSTONE: u8 = 1 << 2;
+    /// The entry marks deletes with `TOMBSTONE`, so its value is just a value, even if it
+    /// happens to be `LEGACY_TOMBSTONE`. Set on everything written since the flag existed.
+    pub const EXPLICIT_TOMBSTONES: u8 = 1 << 3;
+    /// The value is stored compressed with lz4.
+    pub const LZ4: u8 = 1 << 4;
+    /// The value is stored compressed with zstd.
+    pub const ZSTD: u8 = 1 << 5;
+    /// The key and value are encrypted, with compression applied before encryption.
+    pub const ENCRYPTED: u8 = 1 << 6;
+}
+
+/// Value that marked a delete before `flags::TOMBSTONE` existed. Only entries lacking
+/// `flags::EXPLICIT_TOMBSTONES` can be deleted this way.
+pub(crate) const LEGACY_TOMBSTONE: &[u8; 3] = b"\xE2\x98\x97";
+
+/// Combine a timestamp with the entry flags and expiry for storage.
+pub(crate) fn pack_ts(ts: u128, flags: u8, expiry: Option<u128>) -> u128 {
+    ts | (flags as u128) << FLAGS_SHIFT | stored_expiry(expiry) << EXPIRY_SHIFT
+}
+
+/// The expiry as stored, clamped to what every format can hold, with 0 standing for none.
+pub(crate) fn stored_expiry(expiry: Option<u128>) -> u128 {
+    expiry.map_or(0, |expiry| std::cmp::min(expiry, MAX_EXPIRY))
+}
+
+/// Write the timestamp, flags and expiry the way `format` lays them out in entry and hint
+/// headers.
+pub(crate) fn encode_ts(format: Format, ts: u128, flags: u8, expiry: Option<u128>) -> Vec<u8> {
+    match format {
+        Format::Legacy | Format::V1 => format.encode_u128(pack_ts(ts, flags, expiry)).to_vec(),
+        Format::V2 => {
+            let mut encoded = format.encode_u64(ts as u64).to_vec();
+            encoded.push(flags);
+            encode_varint(stored_expiry(expiry) as u64, &mut encoded);
+            encoded
+        }
+    }
+}
+
+/// Read the timestamp, flags and expiry written by `encode_ts`.
+pub(crate) fn read_ts(
+    format: Format,
+    reader: &mut impl Read,
+) -> std::io::Result<(u128, u8, Option<u128>)> {
+    match format {
+        Format::Legacy | Format::V1 => {
+            let mut packed = [0u8; 16];
+            reader.read_exact(&mut packed)?;
+            Ok(unpack_ts(format.decode_u128(packed)))
+        }
+        Format::V2 => {
+            let mut ts = [0u8; 8];
+            reader.read_exact(&mut ts)?;
+            let mut flags = [0u8; 1];
+            reader.read_exact(&mut flags)?;
+            let expiry = read_varint(reader)? as u128;
+            Ok((
+                format.decode_u64(ts) as u128,
+                flags[0],
+                (expiry != 0).then_some(expiry),
+            ))
+        }
+    }
+}
+
+/// Read a size written by `encode_size`.
+pub(crate) fn read_size(format: Format, reader: &mut impl Read) -> std::io::Result<u64> {
+    match format {
+        Format::Legacy | Format::V1 => {
+            let mut size = [0u8; 8];
+            reader.read_exact(&mut size)?;
+            Ok(format.decode_u64(size))
+        }
+        Format::V2 => read_varint(reader),
+    }
+}
+
+/// Write a size the way `format` lays it out in entry and hint headers.
+pub(crate) fn encode_size(format: Format, size: u64) -> Vec<u8> {
+    match format {
+        Format::Legacy | Format::V1 => format.encode_u64(size).to_vec(),
+        Format::V2 => {
+            let mut encoded = Vec::new();
+            encode_varint(size, &mut encoded);
+            encoded
+        }
+    }
+}
+
+/// Split a stored timestamp back into the timestamp proper, the entry flags and the expiry.
+pub(crate) fn unpack_ts(packed: u128) -> (u128, u8, Option<u128>) {
+    let expiry = packed >> EXPIRY_SHIFT;
+    (
+        packed & u64::MAX as u128,
+        (packed >> FLAGS_SHIFT) as u8,
+        (expiry != 0).then_some(expiry),
+    )
+}
 
 // TODO probably should put this in some utils-oriented place...
 fn from_utf8(input: &[u8]) -> &str {
     std::str::from_utf8(input).unwrap_or("UNREPRESENTABLE")
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct LogEntry {
     pub key: Vec<u8>,
     pub val: Vec<u8>,
     pub ts: u128,
+    pub flags: u8,
+    /// When the entry stops being readable, in microseconds since the epoch.
+    pub expiry: Option<u128>,
 }
 
 impl fmt::Display for LogEntry {
@@ -35,13 +161,60 @@ impl fmt::Display for LogEntry {
 }
 
 impl LogEntry {
-    pub fn from_set(key: &[u8], val: &[u8]) -> Result<Self> {
-        let ts: u128 = SystemTime::now().duration_since(UNIX_EPOCH)?.as_micros();
-        Ok(Self {
+    pub fn from_set(key: &[u8], val: &[u8], ts: u128) -> Self {
+        Self {
             key: key.to_vec(),
             val: val.to_vec(),
             ts,
-        })
+            flags: flags::EXPLICIT_TOMBSTONES,
+            expiry: None,
+        }
+    }
+
+    /// Marker deleting `key` as of `ts`.
+    pub fn tombstone(key: &[u8], ts: u128) -> Self {
+        Self {
+            key: key.to_vec(),
+            val: Vec::new(),
+            ts,
+            flags: flags::TOMBSTONE | flags::EXPLICIT_TOMBSTONES,
+            expiry: None,
+        }
+    }
+
+    /// Marker closing a batch of `count` entries written with `ts`. The count is stored
+    /// little-endian, as everything else in current files is.
+    pub fn batch_commit(count: usize, ts: u128) -> Self {
+        Self {
+            key: Vec::new(),
+            val: (count as u64).to_le_bytes().to_vec(),
+            ts,
+            flags: flags::BATCH_COMMIT | flags::EXPLICIT_TOMBSTONES,
+            expiry: None,
+        }
+    }
+
+    pub fn is_expired(&self, now: u128) -> bool {
+        self.expiry.is_some_and(|expiry| expiry <= now)
+    }
+
+    /// Whether the entry deletes its key, either by flag or, in older files, by value.
+    pub fn is_tombstone(&self) -> bool {
+        self.flags & flags::TOMBSTONE != 0
+            || (self.flags & flags::EXPLICIT_TOMBSTONES == 0 && self.val == LEGACY_TOMBSTONE)
+    }
+
+    pub fn in_batch(&self) -> bool {
+        self.flags & flags::IN_BATCH != 0
+    }
+
+    /// Number of entries committed, if this is a batch commit marker.
+    pub fn batch_commit_count(&self) -> Option<usize> {
+        if self.flags & flags::BATCH_COMMIT == 0 {
+            return None;
+        }
+        let count: [u8; 8] = self.val.as_slice().try_into().ok()?;
+        Some(u64::from_le_bytes(count) as usize)
     }
 
     pub fn key_sz(&self) -> u64 {
@@ -53,23 +226,45 @@ impl LogEntry {
     }
 
     pub fn serialize(&self) -> Vec<u8> {
-        let mut serialized = Vec::new();
-        serialized.extend(self.ts.to_ne_bytes());
-        serialized.extend(self.key_sz().to_ne_bytes());
-        serialized.extend(self.val_sz().to_ne_bytes());
+        self.serialize_as(Format::CURRENT)
+    }
+
+    /// Serialize everything but the CRC, the way files in `format` lay it out.
+    pub fn serialize_as(&self, format: Format) -> Vec<u8> {
+        let mut serialized = encode_ts(format, self.ts, self.flags, self.expiry);
+        serialized.extend(encode_size(format, self.key_sz()));
+        serialized.extend(encode_size(format, self.val_sz()));
         serialized.extend(self.key.clone());
         serialized.extend(self.val.clone());
         serialized
     }
 
     pub fn crc(&self) -> u32 {
-        CRC.checksum(self.serialize().as_slice())
+        self.crc_as(Format::CURRENT)
+    }
+
+    /// The CRC the entry would have been written with in `format`.
+    pub fn crc_as(&self, format: Format) -> u32 {
+        CRC.checksum(self.serialize_as(format).as_slice())
     }
 
     pub fn serialize_with_crc(&self) -> Vec<u8> {
+        self.serialize_with_crc_as(Format::CURRENT)
+    }
+
+    /// Serialize the entry in full, the way files in `format` lay it out.
+    pub fn serialize_with_crc_as(&self, format: Format) -> Vec<u8> {
         // TODO don't really need to call `serialize` 2x (the other time in `crc`)
-        let mut serialized: Vec<u8> = self.crc().to_ne_bytes().into_iter().collect();
-        serialized.extend(self.serialize());
+        let mut serialized: Vec<u8> = format.encode_u32(self.crc_as(format)).to_vec();
+        serialized.extend(self.serialize_as(format));
         serialized
     }
 }
+
+/// Microseconds since the epoch, as used for entry timestamps.
+pub(crate) fn now() -> Result<u128> {
+    Ok(SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| Error::io(std::io::ErrorKind::Other, e.to_string()))?
+        .as_micros())
+}
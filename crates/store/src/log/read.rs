@@ -1,10 +1,12 @@
-use std::io::{BufReader, Read};
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, ErrorKind, Read};
 use std::path::PathBuf;
 
-use log::debug;
+use log::{debug, warn};
 
+use crate::error::Error;
 use crate::log::files::FileHandle;
-use crate::log::LogEntry;
+use crate::log::{flags, from_utf8, read_size, read_ts, LogEntry, LEGACY_TOMBSTONE};
 use crate::Result;
 
 pub struct LogReaderItem {
@@ -15,12 +17,15 @@ pub struct LogReaderItem {
 
 impl LogReaderItem {
     pub fn into_key_item_tuple(self) -> (Vec<u8>, crate::keydir::Item) {
+        let key_sz = self.entry.key.len();
         let val_sz = self.entry.val_sz() as usize;
         (
             self.entry.key,
             crate::keydir::Item {
                 path: self.path.clone(),
+                key_sz,
                 ts: self.entry.ts,
+                expiry: self.entry.expiry,
                 val_pos: self.val_pos,
                 val_sz,
             },
@@ -28,58 +33,173 @@ impl LogReaderItem {
     }
 }
 
-// TODO think we just should have a `LogFile` and a `HintFile`, both with their own iterators.
-pub struct HintReader<'a> {
-    reader: BufReader<&'a mut FileHandle>,
+/// Wraps an iterator over log entries, holding back batched entries until their commit
+/// marker is read. Batches that never got committed are dropped, as are the markers.
+pub struct Committed<I> {
+    inner: I,
+    pending: Vec<LogReaderItem>,
+    ready: VecDeque<LogReaderItem>,
 }
 
-impl<'a> HintReader<'a> {
-    pub fn new(handle: &'a mut FileHandle) -> Self {
+impl<I: Iterator<Item = Result<LogReaderItem>>> Committed<I> {
+    pub fn new(inner: I) -> Self {
         Self {
-            reader: BufReader::new(handle),
+            inner,
+            pending: Vec::new(),
+            ready: VecDeque::new(),
+        }
+    }
+
+    fn drop_pending(&mut self) {
+        if let Some(first) = self.pending.first() {
+            warn!(
+                "Dropping uncommitted batch of {} entries at {:?}:{}",
+                self.pending.len(),
+                first.path,
+                first.val_pos
+            );
+            self.pending.clear();
         }
     }
 }
 
-impl<'a> Iterator for HintReader<'a> {
-    type Item = Result<(Vec<u8>, crate::keydir::Item)>;
+impl<I: Iterator<Item = Result<LogReaderItem>>> Iterator for Committed<I> {
+    type Item = Result<LogReaderItem>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let mut buf = [0u8; 16];
-        self.reader.read_exact(&mut buf).ok()?;
-        let ts = u128::from_ne_bytes(buf);
+        loop {
+            if let Some(read) = self.ready.pop_front() {
+                return Some(Ok(read));
+            }
+            let read = match self.inner.next() {
+                Some(Ok(read)) => read,
+                Some(Err(e)) => return Some(Err(e)),
+                None => {
+                    self.drop_pending();
+                    return None;
+                }
+            };
+            if let Some(count) = read.entry.batch_commit_count() {
+                if count == self.pending.len() {
+                    self.ready.extend(self.pending.drain(..));
+                } else {
+                    self.drop_pending();
+                }
+            } else if read.entry.in_batch() {
+                self.pending.push(read);
+            } else {
+                self.drop_pending();
+                return Some(Ok(read));
+            }
+        }
+    }
+}
+
+pub struct HintReaderItem {
+    pub key: Vec<u8>,
+    pub item: crate::keydir::Item,
+    pub flags: u8,
+}
+
+impl HintReaderItem {
+    pub fn is_tombstone(&self) -> bool {
+        self.flags & flags::TOMBSTONE != 0
+    }
+
+    /// Hints don't carry values, so a hint written before `flags::TOMBSTONE` existed could
+    /// stand for a delete. Only the entry itself can tell.
+    pub fn may_be_legacy_tombstone(&self) -> bool {
+        self.flags & flags::EXPLICIT_TOMBSTONES == 0 && self.item.val_sz == LEGACY_TOMBSTONE.len()
+    }
+}
+
+// TODO think we just should have a `LogFile` and a `HintFile`, both with their own iterators.
+pub struct HintReader<'a> {
+    reader: BufReader<&'a mut FileHandle>,
+    offset: u64,
+    /// Length of the log file the hints point into.
+    log_len: u64,
+}
 
-        let mut buf = [0u8; 8];
-        self.reader.read_exact(&mut buf).ok()?;
-        let key_sz = u64::from_ne_bytes(buf) as usize;
+impl<'a> HintReader<'a> {
+    /// Read the hints in `handle`, for a log file of `log_len` bytes.
+    pub fn new(handle: &'a mut FileHandle, log_len: u64) -> Self {
+        Self {
+            offset: handle.offset,
+            reader: BufReader::new(handle),
+            log_len,
+        }
+    }
 
-        let mut buf = [0u8; 8];
-        self.reader.read_exact(&mut buf).ok()?;
-        let val_sz = u64::from_ne_bytes(buf) as usize;
+    fn truncated(&self) -> Error {
+        Error::TruncatedEntry {
+            path: self.reader.get_ref().path.clone(),
+            offset: self.offset,
+        }
+    }
 
-        let mut buf = [0u8; 8];
-        self.reader.read_exact(&mut buf).ok()?;
-        let val_pos = u64::from_ne_bytes(buf) as usize;
+    /// Report a short read as a truncated hint at the current record.
+    fn read_error(&self, e: std::io::Error) -> Error {
+        match e.kind() {
+            ErrorKind::UnexpectedEof => self.truncated(),
+            _ => e.into(),
+        }
+    }
 
-        let mut key = vec![0u8; key_sz];
-        self.reader.read_exact(&mut key).ok()?;
+    fn read_hint(&mut self) -> Result<Option<HintReaderItem>> {
+        if self.reader.fill_buf()?.is_empty() {
+            return Ok(None);
+        }
+        // Where the record starts: past what the file has handed over, less what's buffered.
+        self.offset = self.reader.get_ref().offset - self.reader.buffer().len() as u64;
+        let format = self.reader.get_ref().format();
+        let reader = &mut self.reader;
+        let fields = read_ts(format, reader).and_then(|(ts, flags, expiry)| {
+            let key_sz = read_size(format, reader)?;
+            let val_sz = read_size(format, reader)?;
+            let val_pos = read_size(format, reader)?;
+            Ok((ts, flags, expiry, key_sz, val_sz, val_pos))
+        });
+        let (ts, flags, expiry, key_sz, val_sz, val_pos) =
+            fields.map_err(|e| self.read_error(e))?;
+        // Hints have no checksum, so don't trust the sizes with an allocation, or the item
+        // with a read, before knowing they fit in their files.
+        let key_pos = self.reader.get_ref().offset - self.reader.buffer().len() as u64;
+        if key_sz > self.reader.get_ref().len()?.saturating_sub(key_pos)
+            || val_pos.saturating_add(val_sz) > self.log_len
+        {
+            return Err(self.truncated());
+        }
+        let mut key = vec![0u8; key_sz as usize];
+        self.reader
+            .read_exact(&mut key)
+            .map_err(|e| self.read_error(e))?;
+        let (key_sz, val_sz) = (key_sz as usize, val_sz as usize);
 
-        debug!(
-            "Reading from hint: \"{}\"",
-            std::str::from_utf8(key.as_slice()).ok()?
-        );
+        debug!("Reading from hint: \"{}\"", from_utf8(key.as_slice()));
 
         let mut path = self.reader.get_ref().path.clone();
         path.set_extension("cask");
 
-        Some(Ok((
+        Ok(Some(HintReaderItem {
             key,
-            crate::keydir::Item {
+            item: crate::keydir::Item {
                 path,
+                key_sz,
                 val_sz,
-                val_pos: val_pos as u64,
+                val_pos,
                 ts,
+                expiry,
             },
-        )))
+            flags,
+        }))
+    }
+}
+
+impl<'a> Iterator for HintReader<'a> {
+    type Item = Result<HintReaderItem>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_hint().transpose()
     }
 }
@@ -1,54 +1,298 @@
+use std::collections::BTreeMap;
 use std::path::PathBuf;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
-use log::info;
+use log::{info, warn};
 
 use crate::bitcask::SharedKeyDir;
-use crate::config::StoreConfig;
-use crate::keydir::{Item, KeyDir};
-use crate::log::files::{FileHandle, FileManager};
-use crate::log::read::LogReaderItem;
+use crate::config::{CorruptionPolicy, StoreConfig, SyncMode};
+use crate::error::Error;
+use crate::keydir::KeyDir;
+use crate::log::files::{FileHandle, FileManager, FileStats};
+use crate::log::read::{Committed, LogReaderItem};
+use crate::log::{flags, now, LogEntry};
 
 pub struct MergeResult {
     pub keydir: KeyDir,
+    /// The items those in `keydir` were copied from, which they only replace if the key still
+    /// points at them by the time the merge is committed.
+    pub replaced: KeyDir,
     pub file_manager: FileManager,
+    /// Files found to be corrupt under `CorruptionPolicy::Quarantine`.
+    pub quarantined: Vec<PathBuf>,
+}
+
+/// The parts of a `BitCask` a merge works on, so that merges can also run from the merge
+/// scheduler's thread.
+#[derive(Clone)]
+pub(crate) struct Merger {
+    pub config: Arc<StoreConfig>,
+    pub keydir: SharedKeyDir,
+    pub file_manager: Arc<Mutex<FileManager>>,
+    /// Held for the length of a merge, so that only one runs at a time.
+    pub merge_mutex: Arc<Mutex<()>>,
+}
+
+/// Which of the closed files a merge rewrites. The default picks every one of them.
+#[derive(Clone, Debug, Default)]
+pub struct MergePolicy {
+    /// Pass over files with a smaller share of dead bytes than this, as a percentage.
+    pub min_fragmentation: u8,
+    /// Rewrite at most this many files, the most fragmented first.
+    pub max_files: Option<usize>,
+    /// Rewrite at most this many bytes of files between them. Files too big for what's left
+    /// are passed over for smaller ones.
+    pub max_input_bytes: Option<u64>,
+}
+
+impl MergePolicy {
+    /// Pick the files to merge out of the closed files and their stats.
+    fn select(&self, mut closed: Vec<(PathBuf, FileStats)>) -> Vec<PathBuf> {
+        closed.retain(|(_, stats)| stats.fragmentation() >= self.min_fragmentation as u64);
+        closed
+            .sort_by_key(|(_, stats)| std::cmp::Reverse((stats.fragmentation(), stats.dead_bytes)));
+        let mut input_bytes_left = self.max_input_bytes.unwrap_or(u64::MAX);
+        closed
+            .into_iter()
+            .filter(|(_, stats)| {
+                let input_bytes = stats.live_bytes + stats.dead_bytes;
+                let fits = input_bytes <= input_bytes_left;
+                if fits {
+                    input_bytes_left -= input_bytes;
+                }
+                fits
+            })
+            .take(self.max_files.unwrap_or(usize::MAX))
+            .map(|(path, _)| path)
+            .collect()
+    }
+}
+
+/// How many keys a merge goes through per hold of the `KeyDir`'s write lock when committing.
+const COMMIT_CHUNK: usize = 4096;
+
+impl Merger {
+    /// Rewrite the live entries of the closed files picked by `policy` into new files, and drop
+    /// the old ones.
+    pub fn merge(&self, policy: &MergePolicy) -> crate::Result<()> {
+        // Take mutex to hold throughout this function's scope.
+        let _merge_mutex = self
+            .merge_mutex
+            .try_lock()
+            .map_err(|_| Error::MergeUnderway)?;
+        let closed = self.file_stats();
+        let closed_count = closed.len();
+        let files_to_merge = policy.select(closed);
+        if files_to_merge.is_empty() {
+            return Ok(());
+        }
+        // Older versions of keys may live on in the files left out, for deletes to go on
+        // shadowing.
+        let keep_tombstones = files_to_merge.len() < closed_count;
+        let MergeResult {
+            keydir: merge_keydir,
+            replaced,
+            file_manager: mut merge_file_manager,
+            quarantined,
+        } = merge(
+            self.keydir.clone(),
+            &files_to_merge,
+            self.config.clone(),
+            keep_tombstones,
+        )?;
+        // The merged files are about to replace data that may already be on disk, so they
+        // need to be durable first no matter the `SyncMode`.
+        merge_file_manager.sync_all()?;
+        // Later merges have to be able to pick up every merged file, as files left out of a
+        // merge stop it from dropping deletes.
+        merge_file_manager.close_current()?;
+
+        {
+            let mut file_manager = self.file_manager.lock().unwrap();
+            for (_, handle) in merge_file_manager.inner {
+                file_manager.insert(handle)?;
+            }
+        }
+
+        // Swap the copies in a chunk at a time, so that writes waiting on the `KeyDir` get a
+        // turn in between. The old files stay around for reads until the end.
+        let mut superseded = Vec::new();
+        let mut merged = merge_keydir.data.into_iter().peekable();
+        while merged.peek().is_some() {
+            let mut keydir = self.keydir.write().unwrap();
+            for (key, item) in merged.by_ref().take(COMMIT_CHUNK) {
+                // Writes that went in since the merge read the key win over its copy.
+                let original = replaced.get(&key);
+                match keydir.data.get_mut(&key) {
+                    Some(current)
+                        if original.is_some_and(|original| {
+                            current.path == original.path && current.val_pos == original.val_pos
+                        }) =>
+                    {
+                        *current = item
+                    }
+                    _ => superseded.push(item),
+                }
+            }
+        }
+        // Whatever still points at the merged files didn't survive the merge, either for
+        // having expired or for being corrupt, so forget it to match.
+        self.forget(&files_to_merge);
+
+        // The `KeyDir` is let go by now, as it must never be held while taking the
+        // `FileManager`.
+        let mut file_manager = self.file_manager.lock().unwrap();
+        for item in superseded {
+            file_manager.count_superseded(&item);
+        }
+        for path in files_to_merge {
+            if quarantined.contains(&path) {
+                file_manager.quarantine(&path)?;
+                continue;
+            }
+            if let Some(handle) = file_manager.remove(&path) {
+                std::fs::remove_file(&handle.path)?;
+                let mut hint_path = handle.path.clone();
+                hint_path.set_extension("hint");
+                if hint_path.exists() {
+                    std::fs::remove_file(&hint_path)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Drop the keys pointing into any of `paths` from the `KeyDir`, a chunk of keys at a time.
+    fn forget(&self, paths: &[PathBuf]) {
+        let mut start = Vec::new();
+        loop {
+            let mut keydir = self.keydir.write().unwrap();
+            let mut stale = Vec::new();
+            let mut next = None;
+            for (i, (key, item)) in keydir.range(start..).enumerate() {
+                if i == COMMIT_CHUNK {
+                    next = Some(key.clone());
+                    break;
+                }
+                if paths.contains(&item.path) {
+                    stale.push(key.clone());
+                }
+            }
+            for key in stale {
+                keydir.remove(&key);
+            }
+            match next {
+                Some(next) => start = next,
+                None => return,
+            }
+        }
+    }
+
+    /// The stats of every closed file, which are the ones a merge would rewrite.
+    pub fn file_stats(&self) -> Vec<(PathBuf, FileStats)> {
+        let file_manager = self.file_manager.lock().unwrap();
+        file_manager
+            .iter_closed()
+            .map(|handle| (handle.path.clone(), handle.stats()))
+            .collect()
+    }
 }
 
 /// Actually perform the brunt of the merge.
 /// Iterate over candidates for merge and retain the values which match those
-/// of the keydir in merge files.
+/// of the keydir in merge files. With `keep_tombstones`, the latest delete of each key gets
+/// carried over as well, as do values that have expired, in the form of deletes, so that they
+/// keep hiding older versions in files outside the merge.
 pub fn merge(
     keydir: SharedKeyDir,
     files_to_merge: &Vec<PathBuf>,
     config: Arc<StoreConfig>,
-) -> crate::Result<(KeyDir, FileManager)> {
+    keep_tombstones: bool,
+) -> crate::Result<MergeResult> {
+    let policy = config.corruption_policy;
     let mut new_keydir = KeyDir::default();
-    let mut file_manager: FileManager = FileManager::new(config);
-    let keydir = keydir.read().unwrap();
-    let mut val_pos = 0;
+    let mut replaced = KeyDir::default();
+    let mut quarantined = Vec::new();
+    // Everything gets synced in one go once merged, so there's no need for the writes to
+    // sync along the way however the store is set to.
+    let config = Arc::new(StoreConfig {
+        sync_mode: SyncMode::Os,
+        ..(*config).clone()
+    });
+    let mut file_manager: FileManager = FileManager::new(config)?;
+    let keyring = file_manager.keyring();
+    let now = now()?;
+    // The timestamp of the latest delete of each key to carry over.
+    let mut tombstones = BTreeMap::new();
+    // How many entries went into each new file, for its `FileStats`.
+    let mut entries = BTreeMap::new();
     for path in files_to_merge {
         let handle = FileHandle::new(path.clone(), false)?;
-        // TODO should at least log something about encountering parse errors along the way.
-        for LogReaderItem { entry, .. } in handle.flatten() {
-            if let Some(item) = keydir.get(&entry.key) {
-                if item.ts == entry.ts {
-                    info!("Merging {:?}", entry);
-                    let (path, next_val_pos) =
-                        file_manager.write(entry.serialize_with_crc().as_slice())?;
-                    let item = Item {
-                        path,
-                        val_sz: entry.val_sz() as usize,
-                        val_pos,
-                        ts: entry.ts,
-                    };
-                    val_pos = next_val_pos;
-                    file_manager.write_hint(item.serialize_as_hint(&entry.key).as_slice())?;
-                    // TODO these writes should definitely be from a `BufWriter`...
-                    new_keydir.set(entry.key.clone(), item);
-                }
+        for read in Committed::new(handle) {
+            let LogReaderItem { entry, val_pos, .. } = match read {
+                Ok(read) => read,
+                Err(e) => match policy {
+                    CorruptionPolicy::Fail => return Err(e),
+                    CorruptionPolicy::Skip => {
+                        warn!("Skipping corrupt entry: {}", e);
+                        continue;
+                    }
+                    CorruptionPolicy::Quarantine => {
+                        warn!("Found corrupt entry: {}", e);
+                        quarantined.push(path.clone());
+                        break;
+                    }
+                },
+            };
+            let mut entry = keyring.decrypt(entry, path, val_pos)?;
+            // Only hold the `KeyDir` for as long as it takes to look the key up, so that writes
+            // go on meanwhile. Whatever they change gets the better of the copy on commit.
+            // The key has to point at this very entry, as timestamps are shared by a batch and
+            // kept by merges.
+            let (original, missing) = {
+                let keydir = keydir.read().unwrap();
+                let latest = keydir.get(&entry.key);
+                let original = latest
+                    .filter(|item| item.path == *path && item.val_pos == val_pos)
+                    .cloned();
+                (original, latest.is_none())
+            };
+            let is_latest = original.is_some();
+            if let Some(original) = original.filter(|_| !entry.is_expired(now)) {
+                info!("Merging {:?}", entry);
+                let key = entry.key.clone();
+                replaced.set(key.clone(), original);
+                // Only committed entries make it this far, so they can stand on their own.
+                entry.flags &= !flags::IN_BATCH;
+                // Tombstones aren't in the `KeyDir`, so whatever's left is a plain value.
+                entry.flags |= flags::EXPLICIT_TOMBSTONES;
+                // Compress and encrypt the entry the way new writes would be, which takes
+                // it off any retired encryption key. Last, as encryption covers the flags.
+                let entry = file_manager.encode(entry)?;
+                let item = file_manager.set(&entry)?;
+                file_manager
+                    .write_hint(item.serialize_as_hint(&entry.key, entry.flags).as_slice())?;
+                *entries.entry(item.path.clone()).or_insert(0) += 1;
+                new_keydir.set(key, item);
+            } else if keep_tombstones && (is_latest || entry.is_tombstone() && missing) {
+                let ts = tombstones.entry(entry.key).or_insert(entry.ts);
+                *ts = std::cmp::max(*ts, entry.ts);
             }
         }
     }
+    for (key, ts) in tombstones {
+        let entry = file_manager.encode(LogEntry::tombstone(&key, ts))?;
+        let item = file_manager.set(&entry)?;
+        file_manager.write_hint(item.serialize_as_hint(&entry.key, entry.flags).as_slice())?;
+        *entries.entry(item.path).or_insert(0) += 1;
+    }
+    file_manager.recount(&new_keydir, &entries)?;
 
-    Ok((new_keydir, file_manager))
+    Ok(MergeResult {
+        keydir: new_keydir,
+        replaced,
+        file_manager,
+        quarantined,
+    })
 }
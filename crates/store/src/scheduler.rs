@@ -0,0 +1,97 @@
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{error, info};
+
+use crate::config::StoreConfig;
+use crate::error::Error;
+use crate::merge::{MergePolicy, Merger};
+
+/// Background thread merging whenever the store's fragmentation crosses one of the configured
+/// triggers, for `auto_merge`. Stops when dropped, after any merge it's in the middle of.
+pub(crate) struct MergeScheduler {
+    stop: Option<mpsc::Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl MergeScheduler {
+    pub fn spawn(merger: Merger, interval: Duration) -> Self {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let handle = std::thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                if !merger.config.in_merge_window(utc_hour()) {
+                    continue;
+                }
+                let Some(reason) = merge_reason(&merger) else {
+                    continue;
+                };
+                info!("Starting automatic merge, as {}", reason);
+                match merger.merge(&MergePolicy::default()) {
+                    // A merge started by hand beat us to it, which is just as good.
+                    Ok(()) | Err(Error::MergeUnderway) => {}
+                    Err(e) => error!("Automatic merge failed: {}", e),
+                }
+            }
+        });
+        Self {
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for MergeScheduler {
+    fn drop(&mut self) {
+        // Hanging up wakes the thread, which starts no further merges and exits.
+        self.stop.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Describe the first of the triggers in `merger.config` that fires, if any does.
+fn merge_reason(merger: &Merger) -> Option<String> {
+    let StoreConfig {
+        merge_frag_trigger,
+        merge_dead_bytes_trigger,
+        merge_closed_files_trigger,
+        ..
+    } = *merger.config;
+    let stats = merger.file_stats();
+    if stats.is_empty() {
+        return None;
+    }
+    if let Some(closed_files) = merge_closed_files_trigger {
+        // Files without a dead entry, such as what the last merge wrote, wouldn't come out of
+        // a merge any smaller, so counting them would only have it merge them over and over.
+        // Entries rather than bytes, so that commit markers or a byte count that's off can't
+        // set that going either.
+        let dirty_files = stats.iter().filter(|(_, file)| file.dead_keys > 0).count();
+        if dirty_files >= closed_files {
+            return Some(format!(
+                "there are {} closed files with dead entries",
+                dirty_files
+            ));
+        }
+    }
+    if let Some((path, file)) = stats
+        .iter()
+        .find(|(_, file)| file.fragmentation() >= merge_frag_trigger as u64)
+    {
+        return Some(format!("{:?} is {}% dead", path, file.fragmentation()));
+    }
+    let dead_bytes: u64 = stats.iter().map(|(_, file)| file.dead_bytes).sum();
+    if dead_bytes >= merge_dead_bytes_trigger {
+        return Some(format!("there are {} dead bytes", dead_bytes));
+    }
+    None
+}
+
+fn utc_hour() -> u8 {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    (secs / 3600 % 24) as u8
+}
@@ -0,0 +1,42 @@
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use log::error;
+
+use crate::log::files::FileManager;
+
+/// Background thread syncing the current log file on a fixed interval, for
+/// `SyncMode::Interval`. Stops when dropped.
+pub(crate) struct Syncer {
+    stop: Option<mpsc::Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Syncer {
+    pub fn spawn(file_manager: Arc<Mutex<FileManager>>, interval: Duration) -> Self {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let handle = std::thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                if let Err(e) = file_manager.lock().unwrap().sync() {
+                    error!("Background sync failed: {}", e);
+                }
+            }
+        });
+        Self {
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for Syncer {
+    fn drop(&mut self) {
+        // Hanging up wakes the thread, which syncs nothing further and exits.
+        self.stop.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
@@ -1,9 +1,17 @@
 use std::ffi::OsStr;
+#[cfg(unix)]
+use std::os::unix::fs::FileExt;
 use std::sync::Arc;
+use std::time::Duration;
 
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
-use store::{BitCask, StoreConfig};
+use store::keydir::Item;
+use store::log::format::{Format, MAGIC};
+use store::log::{flags, LogEntry};
+use store::{
+    BitCask, Compression, CorruptionPolicy, Error, MergePolicy, StoreConfig, SyncMode, WriteBatch,
+};
 use tempfile::{tempdir, TempDir};
 
 pub fn random_bytes(n: usize) -> Vec<u8> {
@@ -16,6 +24,7 @@ fn default_bitcask() -> (BitCask, TempDir) {
     let cfg = StoreConfig {
         log_dir: dir.path().to_path_buf(),
         max_log_file_size: 1000,
+        ..Default::default()
     };
     // Return to ensure tempdir does not go out of scope.
     (BitCask::new(Arc::new(cfg)).unwrap(), dir)
@@ -59,6 +68,7 @@ fn run_test(cfg: Option<Arc<StoreConfig>>, test: impl FnOnce(&mut BitCask)) {
     let default_cfg = StoreConfig {
         log_dir: dir.path().to_path_buf(),
         max_log_file_size: 1000,
+        ..Default::default()
     };
     let cfg = cfg.unwrap_or_else(|| Arc::new(default_cfg));
     let mut bitcask = BitCask::new(cfg).unwrap();
@@ -72,6 +82,7 @@ fn test_read_existing_on_init() {
     let cfg = StoreConfig {
         log_dir: dir.path().to_path_buf(),
         max_log_file_size: 1000,
+        ..Default::default()
     };
     let cfg = Arc::new(cfg);
     let key = b"foo";
@@ -94,6 +105,7 @@ fn test_merge() {
     let cfg = Arc::new(StoreConfig {
         log_dir: log_dir.clone(),
         max_log_file_size: 1000,
+        ..Default::default()
     });
 
     let vals: Vec<_> = (0..50).map(|_| random_bytes(25)).collect();
@@ -129,3 +141,1695 @@ fn test_merge() {
         );
     });
 }
+
+fn count_cask_files(dir: &std::path::Path) -> usize {
+    std::fs::read_dir(dir)
+        .unwrap()
+        .flatten()
+        .filter(|f| f.path().extension() == Some(OsStr::new("cask")))
+        .count()
+}
+
+fn auto_merge_config(log_dir: &std::path::Path) -> StoreConfig {
+    StoreConfig {
+        log_dir: log_dir.to_path_buf(),
+        max_log_file_size: 1000,
+        auto_merge: true,
+        merge_check_interval_ms: 10,
+        ..Default::default()
+    }
+}
+
+/// With `auto_merge` on, overwriting a key until its closed files are mostly dead should get
+/// them merged away in the background.
+#[test]
+fn test_auto_merge() {
+    let dir = tempdir().unwrap();
+    let cfg = Arc::new(auto_merge_config(dir.path()));
+    let vals: Vec<_> = (0..50).map(|_| random_bytes(25)).collect();
+    run_test(Some(cfg), |bitcask| {
+        for val in &vals {
+            bitcask.set(b"foo", val).unwrap();
+        }
+        let before = count_cask_files(dir.path());
+        assert!(before > 2);
+        for _ in 0..500 {
+            if count_cask_files(dir.path()) < before {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(count_cask_files(dir.path()) < before);
+        assert_eq!(&bitcask.get(b"foo").unwrap(), vals.last().unwrap());
+        // Merging by hand is refused only while the background one is running.
+        assert!(matches!(
+            bitcask.merge(),
+            Ok(()) | Err(Error::MergeUnderway)
+        ));
+    });
+}
+
+/// No automatic merge should start outside the merge window, and a window that makes no sense
+/// should be refused.
+#[test]
+fn test_auto_merge_window() {
+    let dir = tempdir().unwrap();
+    let hour = (std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        / 3600
+        % 24) as u8;
+    let cfg = Arc::new(StoreConfig {
+        // Only the hour half a day from now, so not for the next few seconds either.
+        merge_window_start_hour: Some((hour + 12) % 24),
+        merge_window_end_hour: Some((hour + 13) % 24),
+        ..auto_merge_config(dir.path())
+    });
+    run_test(Some(cfg), |bitcask| {
+        for _ in 0..50 {
+            bitcask.set(b"foo", &random_bytes(25)).unwrap();
+        }
+        let before = count_cask_files(dir.path());
+        std::thread::sleep(Duration::from_millis(200));
+        assert_eq!(count_cask_files(dir.path()), before);
+    });
+
+    let cfg = StoreConfig {
+        merge_window_start_hour: Some(24),
+        merge_window_end_hour: Some(2),
+        ..auto_merge_config(dir.path())
+    };
+    assert!(matches!(
+        BitCask::new(Arc::new(cfg)),
+        Err(Error::InvalidConfig(_))
+    ));
+}
+
+/// The closed files trigger should pass over files without dead entries, so that a store
+/// whose live data alone fills that many files doesn't merge them again and again.
+#[test]
+fn test_auto_merge_closed_files_trigger() {
+    // Whatever a merge writes counts as compact, encrypted or not.
+    for encryption_key in [None, Some("11".repeat(32))] {
+        let dir = tempdir().unwrap();
+        let cfg = Arc::new(StoreConfig {
+            merge_closed_files_trigger: Some(2),
+            merge_frag_trigger: 100,
+            merge_dead_bytes_trigger: u64::MAX,
+            encryption_key,
+            ..auto_merge_config(dir.path())
+        });
+        let cask_files = || {
+            let mut files: Vec<_> = std::fs::read_dir(dir.path())
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .filter(|path| path.extension().is_some_and(|ext| ext == "cask"))
+                .collect();
+            files.sort();
+            files
+        };
+        run_test(Some(cfg), |bitcask| {
+            for i in 0..100u32 {
+                bitcask.set(&i.to_le_bytes(), &random_bytes(25)).unwrap();
+            }
+            let written = cask_files();
+            assert!(written.len() > 4);
+            std::thread::sleep(Duration::from_millis(200));
+            assert_eq!(cask_files(), written);
+
+            for i in 0..100u32 {
+                bitcask.set(&i.to_le_bytes(), b"bar").unwrap();
+            }
+            let settled = || {
+                let stats = bitcask.file_stats();
+                stats.values().filter(|stats| stats.dead_keys > 0).count() < 2
+            };
+            for _ in 0..500 {
+                if settled() {
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            assert!(settled());
+            let merged = cask_files();
+            assert!(!merged.starts_with(&written[..1]));
+            assert!(merged.len() > 2);
+            std::thread::sleep(Duration::from_millis(200));
+            assert_eq!(cask_files(), merged);
+            for i in 0..100u32 {
+                assert_eq!(bitcask.get(&i.to_le_bytes()).unwrap(), b"bar");
+            }
+        });
+    }
+}
+
+/// Merging should only carry over the exact entries keys point at, not older copies of them
+/// sharing their timestamp, as when a file has been copied.
+#[test]
+fn test_merge_copies_only_entries_in_keydir() {
+    let dir = tempdir().unwrap();
+    let cfg = Arc::new(StoreConfig {
+        log_dir: dir.path().to_path_buf(),
+        ..Default::default()
+    });
+    run_test(Some(cfg.clone()), |bitcask| {
+        let mut batch = WriteBatch::new();
+        batch.set(b"a", b"1");
+        batch.set(b"b", b"2");
+        bitcask.write_batch(batch).unwrap();
+    });
+    let cask_file = only_cask_file(dir.path());
+    let stem: u128 = cask_file
+        .file_stem()
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    std::fs::copy(&cask_file, dir.path().join(format!("{}.cask", stem + 1))).unwrap();
+
+    run_test(Some(cfg), |bitcask| {
+        bitcask.merge().unwrap();
+        let stats = bitcask.file_stats();
+        assert_eq!(stats.values().map(|stats| stats.live_keys).sum::<u64>(), 2);
+        assert_eq!(stats.values().map(|stats| stats.dead_keys).sum::<u64>(), 0);
+        assert_eq!(bitcask.get(b"a").unwrap(), b"1");
+        assert_eq!(bitcask.get(b"b").unwrap(), b"2");
+    });
+}
+
+/// Overwrites and deletes should move entries from live to dead in their file's stats, which
+/// should account for every byte of the file and come out the same once rebuilt on startup.
+#[test]
+fn test_file_stats() {
+    // Encryption makes keys bigger on disk than they are, which has to be counted too.
+    for encryption_key in [None, Some("11".repeat(32))] {
+        let dir = tempdir().unwrap();
+        let cfg = Arc::new(StoreConfig {
+            log_dir: dir.path().to_path_buf(),
+            max_log_file_size: 10_000,
+            encryption_key,
+            ..Default::default()
+        });
+        let stats_of_only_file = |bitcask: &BitCask| {
+            let stats = bitcask.file_stats();
+            assert_eq!(stats.len(), 1);
+            let (path, stats) = stats.into_iter().next().unwrap();
+            let data_len = std::fs::metadata(path).unwrap().len() - 8;
+            assert_eq!(stats.live_bytes + stats.dead_bytes, data_len);
+            stats
+        };
+        let assert_compact = |bitcask: &BitCask| {
+            let stats = stats_of_only_file(bitcask);
+            assert_eq!(
+                (stats.live_keys, stats.dead_keys, stats.dead_bytes),
+                (1, 0, 0)
+            );
+            assert_eq!(stats.fragmentation(), 0);
+        };
+
+        let mut before = None;
+        run_test(Some(cfg.clone()), |bitcask| {
+            bitcask.set(b"foo", b"bar").unwrap();
+            bitcask.set(b"baz", b"quux").unwrap();
+            let mut batch = WriteBatch::new();
+            batch.set(b"foo", b"barbar").delete(b"baz");
+            bitcask.write_batch(batch).unwrap();
+
+            let stats = stats_of_only_file(bitcask);
+            assert_eq!((stats.live_keys, stats.dead_keys), (1, 3));
+            before = Some(stats);
+        });
+        run_test(Some(cfg.clone()), |bitcask| {
+            assert_eq!(Some(stats_of_only_file(bitcask)), before);
+            bitcask.merge().unwrap();
+            assert_compact(bitcask);
+        });
+        run_test(Some(cfg), |bitcask| assert_compact(bitcask));
+    }
+}
+
+/// A merge picking only the fragmented files should leave the mostly live ones be, without
+/// deletes or expiries in the merged files letting older versions in the others back.
+#[test]
+fn test_selective_merge() {
+    let dir = tempdir().unwrap();
+    let cfg = Arc::new(StoreConfig {
+        log_dir: dir.path().to_path_buf(),
+        max_log_file_size: 1000,
+        ..Default::default()
+    });
+    run_test(Some(cfg.clone()), |bitcask| {
+        for i in 0..30 {
+            bitcask
+                .set(format!("live{}", i).as_bytes(), &random_bytes(20))
+                .unwrap();
+        }
+        bitcask.set(b"doomed", b"old").unwrap();
+        bitcask.set(b"ttl", b"old").unwrap();
+    });
+    run_test(Some(cfg.clone()), |bitcask| {
+        bitcask.delete(b"doomed").unwrap();
+        bitcask
+            .set_with_ttl(b"ttl", b"new", Duration::from_millis(1))
+            .unwrap();
+        for _ in 0..60 {
+            bitcask.set(b"hot", &random_bytes(25)).unwrap();
+        }
+        bitcask.set(b"hot", b"last").unwrap();
+    });
+    run_test(Some(cfg.clone()), |bitcask| {
+        std::thread::sleep(Duration::from_millis(5));
+        let (fragmented, live): (Vec<_>, Vec<_>) = bitcask
+            .file_stats()
+            .into_iter()
+            .partition(|(_, stats)| stats.fragmentation() >= 50);
+        assert!(fragmented.len() > 2 && !live.is_empty());
+
+        let policy = MergePolicy {
+            min_fragmentation: 50,
+            max_files: Some(1),
+            ..Default::default()
+        };
+        bitcask.merge_with(&policy).unwrap();
+        let remaining = fragmented.iter().filter(|(path, _)| path.exists()).count();
+        assert_eq!(remaining, fragmented.len() - 1);
+
+        let policy = MergePolicy {
+            min_fragmentation: 50,
+            ..Default::default()
+        };
+        bitcask.merge_with(&policy).unwrap();
+        assert!(live.iter().all(|(path, _)| path.exists()));
+        assert!(fragmented.iter().all(|(path, _)| !path.exists()));
+    });
+    run_test(Some(cfg), |bitcask| {
+        assert!(matches!(bitcask.get(b"doomed"), Err(Error::KeyMiss)));
+        assert!(matches!(bitcask.get(b"ttl"), Err(Error::KeyMiss)));
+        assert_eq!(bitcask.get(b"hot").unwrap(), b"last");
+        assert_eq!(bitcask.len().unwrap(), 31);
+    });
+}
+
+/// Misses should be reported as a typed `Error::KeyMiss`.
+#[test]
+fn test_key_miss() {
+    run_test(None, |bitcask| {
+        assert!(matches!(bitcask.get(b"nope"), Err(Error::KeyMiss)));
+
+        bitcask.set(b"foo", b"bar").unwrap();
+        bitcask.delete(b"foo").unwrap();
+        assert!(matches!(bitcask.get(b"foo"), Err(Error::KeyMiss)));
+    });
+}
+
+/// A write torn by a crash should be truncated away on startup, keeping everything before it.
+#[cfg(unix)]
+#[test]
+fn test_torn_write_truncated_on_init() {
+    // Either cut the last entry short, or corrupt its final byte.
+    for corrupt in [
+        |file: &std::fs::File, len: u64| file.set_len(len - 1).unwrap(),
+        |file: &std::fs::File, len: u64| file.write_all_at(b"!", len - 1).unwrap(),
+    ] {
+        let dir = tempdir().unwrap();
+        let cfg = Arc::new(StoreConfig {
+            log_dir: dir.path().to_path_buf(),
+            max_log_file_size: 1000,
+            ..Default::default()
+        });
+        run_test(Some(cfg.clone()), |bitcask| {
+            bitcask.set(b"foo", b"bar").unwrap();
+            bitcask.set(b"baz", b"quux").unwrap();
+        });
+
+        let cask_file = only_cask_file(dir.path());
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&cask_file)
+            .unwrap();
+        let len = file.metadata().unwrap().len();
+        corrupt(&file, len);
+
+        run_test(Some(cfg), |bitcask| {
+            assert_eq!(bitcask.get(b"foo").unwrap(), b"bar");
+            assert!(matches!(bitcask.get(b"baz"), Err(Error::KeyMiss)));
+            bitcask.set(b"baz", b"again").unwrap();
+            assert_eq!(bitcask.get(b"baz").unwrap(), b"again");
+        });
+        // Just the file header and the first entry survive: 16 bytes of entry header for sizes
+        // this small, then the key and value.
+        assert_eq!(std::fs::metadata(&cask_file).unwrap().len(), 8 + 16 + 6);
+    }
+}
+
+/// Prefix scans should yield live entries in byte order, in either direction.
+#[test]
+fn test_scan_prefix() {
+    run_test(None, |bitcask| {
+        for key in [
+            "user:2:profile",
+            "user:1:profile",
+            "user:10:name",
+            "users",
+            "other",
+        ] {
+            bitcask.set(key.as_bytes(), key.as_bytes()).unwrap();
+        }
+        bitcask.delete(b"user:10:name").unwrap();
+
+        let keys: Vec<_> = bitcask
+            .scan_prefix(b"user:")
+            .map(|read| read.unwrap().0)
+            .collect();
+        assert_eq!(
+            keys,
+            vec![b"user:1:profile".to_vec(), b"user:2:profile".to_vec()]
+        );
+
+        let reversed: Vec<_> = bitcask
+            .scan_prefix(b"user:")
+            .rev()
+            .map(|read| read.unwrap())
+            .collect();
+        assert_eq!(
+            reversed,
+            vec![
+                (b"user:2:profile".to_vec(), b"user:2:profile".to_vec()),
+                (b"user:1:profile".to_vec(), b"user:1:profile".to_vec()),
+            ]
+        );
+    });
+}
+
+/// Range scans should respect their bounds and skip deleted keys.
+#[test]
+fn test_range() {
+    run_test(None, |bitcask| {
+        for key in [b"a", b"b", b"c", b"d", b"e"] {
+            bitcask.set(key, key).unwrap();
+        }
+        bitcask.delete(b"c").unwrap();
+
+        let keys: Vec<_> = bitcask
+            .range(b"b".to_vec()..b"e".to_vec())
+            .map(|read| read.unwrap().0)
+            .collect();
+        assert_eq!(keys, vec![b"b".to_vec(), b"d".to_vec()]);
+
+        let keys: Vec<_> = bitcask
+            .range(b"b".to_vec()..)
+            .rev()
+            .map(|read| read.unwrap().0)
+            .collect();
+        assert_eq!(keys, vec![b"e".to_vec(), b"d".to_vec(), b"b".to_vec()]);
+    });
+}
+
+/// `keys`, `iter` and `len` should only reflect live entries.
+#[test]
+fn test_iteration() {
+    run_test(None, |bitcask| {
+        assert!(bitcask.is_empty().unwrap());
+        for i in 0..20 {
+            let key = format!("key{:02}", i);
+            bitcask.set(key.as_bytes(), &random_bytes(25)).unwrap();
+            bitcask.set(key.as_bytes(), key.as_bytes()).unwrap();
+        }
+        for i in (0..20).step_by(2) {
+            bitcask.delete(format!("key{:02}", i).as_bytes()).unwrap();
+        }
+
+        assert_eq!(bitcask.len().unwrap(), 10);
+        let expected: Vec<_> = (1..20)
+            .step_by(2)
+            .map(|i| format!("key{:02}", i).into_bytes())
+            .collect();
+        let keys: Vec<_> = bitcask.keys().map(|key| key.unwrap()).collect();
+        assert_eq!(keys, expected);
+        for read in bitcask.iter() {
+            let (key, val) = read.unwrap();
+            assert_eq!(key, val);
+        }
+    });
+}
+
+/// Iteration should keep returning correct values while writes and merges run alongside.
+#[test]
+fn test_iteration_during_writes_and_merge() {
+    run_test(None, |bitcask| {
+        let bitcask = &*bitcask;
+        for i in 0..100 {
+            let key = format!("key{:03}", i);
+            bitcask.set(key.as_bytes(), key.as_bytes()).unwrap();
+        }
+
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for i in 0..100 {
+                    let key = format!("key{:03}", i);
+                    bitcask.set(key.as_bytes(), key.as_bytes()).unwrap();
+                }
+            });
+            s.spawn(|| {
+                for _ in 0..5 {
+                    match bitcask.merge() {
+                        Ok(()) | Err(Error::MergeUnderway) => {}
+                        Err(e) => panic!("{}", e),
+                    }
+                }
+            });
+            for _ in 0..5 {
+                let mut count = 0;
+                for read in bitcask.iter() {
+                    let (key, val) = read.unwrap();
+                    assert_eq!(key, val);
+                    count += 1;
+                }
+                assert_eq!(count, 100);
+            }
+        });
+        assert_eq!(bitcask.len().unwrap(), 100);
+    });
+}
+
+/// Concurrent `get`s keep finding values while writes rotate files and merges move values
+/// around underneath them.
+#[test]
+fn test_concurrent_gets() {
+    run_test(None, |bitcask| {
+        let bitcask = &*bitcask;
+        let key = |i: usize| format!("key{:02}", i % 50);
+        for i in 0..50 {
+            bitcask.set(key(i).as_bytes(), key(i).as_bytes()).unwrap();
+        }
+
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for i in 0..500 {
+                    let val = format!("{}-{}", key(i), i);
+                    bitcask.set(key(i).as_bytes(), val.as_bytes()).unwrap();
+                }
+            });
+            s.spawn(|| {
+                for _ in 0..5 {
+                    match bitcask.merge() {
+                        Ok(()) | Err(Error::MergeUnderway) => {}
+                        Err(e) => panic!("{}", e),
+                    }
+                }
+            });
+            for t in 0..4 {
+                s.spawn(move || {
+                    for i in 0..500 {
+                        let key = key(t * 13 + i);
+                        let val = bitcask.get(key.as_bytes()).unwrap();
+                        assert!(val.starts_with(key.as_bytes()));
+                    }
+                });
+            }
+        });
+        for i in 450..500 {
+            let val = format!("{}-{}", key(i), i);
+            assert_eq!(bitcask.get(key(i).as_bytes()).unwrap(), val.as_bytes());
+        }
+    });
+}
+
+/// Writes landing while merges run should never be rolled back by the merges committing their
+/// older copies of the same keys, neither in memory nor once reloaded from disk.
+#[test]
+fn test_merge_during_concurrent_writes() {
+    let dir = tempdir().unwrap();
+    let cfg = Arc::new(StoreConfig {
+        log_dir: dir.path().to_path_buf(),
+        max_log_file_size: 2000,
+        ..Default::default()
+    });
+    let key = |t: usize, i: usize| format!("key{}-{:02}", t, i % 20);
+    // Every writer ends on a delete of the keys with an odd index.
+    let check = |bitcask: &BitCask| {
+        for t in 0..4 {
+            for i in 0..20 {
+                let key = key(t, i);
+                match bitcask.get(key.as_bytes()) {
+                    Ok(val) => assert_eq!(val, format!("{}-last", key).as_bytes()),
+                    Err(Error::KeyMiss) => assert_eq!(i % 2, 1),
+                    Err(e) => panic!("{}", e),
+                }
+            }
+        }
+        assert_eq!(bitcask.len().unwrap(), 40);
+    };
+    run_test(Some(cfg.clone()), |bitcask| {
+        let bitcask = &*bitcask;
+        let writing = std::sync::atomic::AtomicUsize::new(4);
+        std::thread::scope(|s| {
+            for t in 0..4 {
+                let writing = &writing;
+                s.spawn(move || {
+                    for i in 0..400 {
+                        let val = format!("{}-{}", key(t, i), i);
+                        bitcask.set(key(t, i).as_bytes(), val.as_bytes()).unwrap();
+                    }
+                    for i in 0..20 {
+                        let val = format!("{}-last", key(t, i));
+                        bitcask.set(key(t, i).as_bytes(), val.as_bytes()).unwrap();
+                        if i % 2 == 1 {
+                            bitcask.delete(key(t, i).as_bytes()).unwrap();
+                        }
+                    }
+                    writing.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+                });
+            }
+            while writing.load(std::sync::atomic::Ordering::SeqCst) > 0 {
+                match bitcask.merge() {
+                    Ok(()) | Err(Error::MergeUnderway) => {}
+                    Err(e) => panic!("{}", e),
+                }
+            }
+        });
+        check(bitcask);
+        bitcask.merge().unwrap();
+        check(bitcask);
+    });
+    run_test(Some(cfg), |bitcask| check(bitcask));
+}
+
+/// Find the single `.cask` file in `dir`.
+fn only_cask_file(dir: &std::path::Path) -> std::path::PathBuf {
+    let mut cask_files: Vec<_> = std::fs::read_dir(dir)
+        .unwrap()
+        .flatten()
+        .map(|f| f.path())
+        .filter(|path| path.extension() == Some(OsStr::new("cask")))
+        .collect();
+    assert_eq!(cask_files.len(), 1);
+    cask_files.pop().unwrap()
+}
+
+/// Batches should apply all their operations, and survive a restart and a merge.
+#[test]
+fn test_write_batch() {
+    let dir = tempdir().unwrap();
+    let cfg = Arc::new(StoreConfig {
+        log_dir: dir.path().to_path_buf(),
+        max_log_file_size: 1000,
+        ..Default::default()
+    });
+    run_test(Some(cfg.clone()), |bitcask| {
+        bitcask.set(b"gone", b"soon").unwrap();
+        let mut batch = WriteBatch::new();
+        batch
+            .set(b"foo", b"bar")
+            .set(b"baz", b"quux")
+            .delete(b"gone");
+        bitcask.write_batch(batch).unwrap();
+
+        assert_eq!(bitcask.get(b"foo").unwrap(), b"bar");
+        assert_eq!(bitcask.get(b"baz").unwrap(), b"quux");
+        assert!(matches!(bitcask.get(b"gone"), Err(Error::KeyMiss)));
+    });
+    run_test(Some(cfg.clone()), |bitcask| {
+        bitcask.merge().unwrap();
+    });
+    run_test(Some(cfg), |bitcask| {
+        assert_eq!(bitcask.get(b"foo").unwrap(), b"bar");
+        assert_eq!(bitcask.get(b"baz").unwrap(), b"quux");
+        assert!(matches!(bitcask.get(b"gone"), Err(Error::KeyMiss)));
+        assert_eq!(bitcask.len().unwrap(), 2);
+    });
+}
+
+/// Batch commit markers should store their count little-endian on every platform, so that
+/// files move between byte orders intact.
+#[test]
+fn test_batch_commit_count_encoding() {
+    let marker = LogEntry::batch_commit(3, 42);
+    assert_eq!(marker.val, [3, 0, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(marker.batch_commit_count(), Some(3));
+
+    let dir = tempdir().unwrap();
+    let cfg = Arc::new(StoreConfig {
+        log_dir: dir.path().to_path_buf(),
+        ..Default::default()
+    });
+    run_test(Some(cfg), |bitcask| {
+        let mut batch = WriteBatch::new();
+        batch.set(b"foo", b"bar").set(b"baz", b"quux");
+        bitcask.write_batch(batch).unwrap();
+    });
+    // The marker comes last, with the count as its value.
+    let cask = std::fs::read(only_cask_file(dir.path())).unwrap();
+    assert_eq!(cask[cask.len() - 8..], 2u64.to_le_bytes());
+}
+
+/// A batch whose commit marker never made it to disk should be ignored on startup.
+#[test]
+fn test_uncommitted_batch_ignored() {
+    let dir = tempdir().unwrap();
+    let cfg = Arc::new(StoreConfig {
+        log_dir: dir.path().to_path_buf(),
+        max_log_file_size: 1000,
+        ..Default::default()
+    });
+    run_test(Some(cfg.clone()), |bitcask| {
+        bitcask.set(b"foo", b"before").unwrap();
+        let mut batch = WriteBatch::new();
+        batch.set(b"foo", b"after").set(b"bar", b"baz");
+        bitcask.write_batch(batch).unwrap();
+    });
+
+    // Chop off just the commit marker, leaving the batch's entries whole, so that it's the
+    // missing marker that gets the batch ignored rather than a torn tail.
+    let cask_file = only_cask_file(dir.path());
+    let marker_sz = LogEntry::batch_commit(2, 0).serialize_with_crc().len() as u64;
+    let file = std::fs::OpenOptions::new()
+        .write(true)
+        .open(&cask_file)
+        .unwrap();
+    let len = file.metadata().unwrap().len() - marker_sz;
+    file.set_len(len).unwrap();
+
+    run_test(Some(cfg), |bitcask| {
+        assert_eq!(bitcask.get(b"foo").unwrap(), b"before");
+        assert!(matches!(bitcask.get(b"bar"), Err(Error::KeyMiss)));
+    });
+    assert_eq!(std::fs::metadata(&cask_file).unwrap().len(), len);
+}
+
+/// Conditional writes should only go through when their precondition holds.
+#[test]
+fn test_conditional_writes() {
+    run_test(None, |bitcask| {
+        assert!(matches!(
+            bitcask.set_if_present(b"foo", b"bar"),
+            Err(Error::KeyMiss)
+        ));
+        let v1 = bitcask.set_if_absent(b"foo", b"bar").unwrap();
+        assert!(matches!(
+            bitcask.set_if_absent(b"foo", b"baz"),
+            Err(Error::KeyExists)
+        ));
+        assert_eq!(
+            bitcask.get_versioned(b"foo").unwrap(),
+            (b"bar".to_vec(), v1)
+        );
+
+        let v2 = bitcask.compare_and_set(b"foo", v1, b"baz").unwrap();
+        assert_ne!(v1, v2);
+        assert!(matches!(
+            bitcask.compare_and_set(b"foo", v1, b"quux"),
+            Err(Error::VersionMismatch)
+        ));
+        assert_eq!(bitcask.get(b"foo").unwrap(), b"baz");
+
+        bitcask.set_if_present(b"foo", b"quux").unwrap();
+        bitcask.delete(b"foo").unwrap();
+        bitcask.set_if_absent(b"foo", b"again").unwrap();
+        assert_eq!(bitcask.get(b"foo").unwrap(), b"again");
+    });
+}
+
+/// Concurrent read-modify-write cycles through `compare_and_set` should lose no updates.
+#[test]
+fn test_compare_and_set_concurrent() {
+    run_test(None, |bitcask| {
+        let bitcask = &*bitcask;
+        bitcask.set(b"counter", b"0").unwrap();
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..25 {
+                        loop {
+                            let (val, version) = bitcask.get_versioned(b"counter").unwrap();
+                            let n: u64 = std::str::from_utf8(&val).unwrap().parse().unwrap();
+                            let next = (n + 1).to_string();
+                            match bitcask.compare_and_set(b"counter", version, next.as_bytes()) {
+                                Ok(_) => break,
+                                Err(Error::VersionMismatch) => continue,
+                                Err(e) => panic!("{}", e),
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(bitcask.get(b"counter").unwrap(), b"100");
+    });
+}
+
+/// Writes committed together from many threads should each land, durably and in order, with
+/// conditional writes still seeing the writes grouped ahead of them.
+#[test]
+fn test_group_commit() {
+    let dir = tempdir().unwrap();
+    let cfg = Arc::new(StoreConfig {
+        log_dir: dir.path().to_path_buf(),
+        max_log_file_size: 4000,
+        sync_mode: SyncMode::Always,
+        ..Default::default()
+    });
+    run_test(Some(cfg.clone()), |bitcask| {
+        let bitcask = &*bitcask;
+        let winners: usize = std::thread::scope(|s| {
+            let threads: Vec<_> = (0..8)
+                .map(|t| {
+                    s.spawn(move || {
+                        for i in 0..50 {
+                            let key = format!("key-{}-{}", t, i);
+                            bitcask.set(key.as_bytes(), key.as_bytes()).unwrap();
+                        }
+                        let mut batch = WriteBatch::new();
+                        batch.set(format!("batch-{}", t).as_bytes(), b"set");
+                        batch.delete(format!("key-{}-0", t).as_bytes());
+                        bitcask.write_batch(batch).unwrap();
+                        match bitcask.set_if_absent(b"winner", &[t as u8]) {
+                            Ok(_) => 1,
+                            Err(Error::KeyExists) => 0,
+                            Err(e) => panic!("{}", e),
+                        }
+                    })
+                })
+                .collect();
+            threads.into_iter().map(|t| t.join().unwrap()).sum()
+        });
+        assert_eq!(winners, 1);
+    });
+
+    run_test(Some(cfg), |bitcask| {
+        assert_eq!(bitcask.len().unwrap(), 8 * 50 + 1);
+        for t in 0..8 {
+            assert!(matches!(
+                bitcask.get(format!("key-{}-0", t).as_bytes()),
+                Err(Error::KeyMiss)
+            ));
+            for i in 1..50 {
+                let key = format!("key-{}-{}", t, i);
+                assert_eq!(bitcask.get(key.as_bytes()).unwrap(), key.as_bytes());
+            }
+            assert_eq!(
+                bitcask.get(format!("batch-{}", t).as_bytes()).unwrap(),
+                b"set"
+            );
+        }
+    });
+}
+
+/// Keys set with a TTL should read as missing once it runs out.
+#[test]
+fn test_ttl() {
+    run_test(None, |bitcask| {
+        bitcask.set(b"forever", b"young").unwrap();
+        bitcask
+            .set_with_ttl(b"session", b"data", Duration::from_millis(100))
+            .unwrap();
+
+        assert_eq!(bitcask.ttl(b"forever").unwrap(), None);
+        let ttl = bitcask.ttl(b"session").unwrap().unwrap();
+        assert!(ttl <= Duration::from_millis(100));
+        assert_eq!(bitcask.get(b"session").unwrap(), b"data");
+        assert_eq!(bitcask.len().unwrap(), 2);
+
+        std::thread::sleep(Duration::from_millis(150));
+        assert!(matches!(bitcask.get(b"session"), Err(Error::KeyMiss)));
+        assert!(matches!(bitcask.ttl(b"session"), Err(Error::KeyMiss)));
+        assert_eq!(bitcask.len().unwrap(), 1);
+        bitcask.set_if_absent(b"session", b"fresh").unwrap();
+    });
+}
+
+/// A TTL too long for the files to hold is cut short to the latest expiry they can, and
+/// comes out the same after a restart.
+#[test]
+fn test_ttl_clamped() {
+    let dir = tempdir().unwrap();
+    let cfg = Arc::new(StoreConfig {
+        log_dir: dir.path().to_path_buf(),
+        ..Default::default()
+    });
+    let mut before = Duration::ZERO;
+    run_test(Some(cfg.clone()), |bitcask| {
+        bitcask.set_with_ttl(b"foo", b"bar", Duration::MAX).unwrap();
+        before = bitcask.ttl(b"foo").unwrap().unwrap();
+        assert!(before < Duration::from_micros(1 << 56));
+        assert!(before > Duration::from_secs(2000 * 365 * 24 * 3600));
+    });
+    run_test(Some(cfg), |bitcask| {
+        let after = bitcask.ttl(b"foo").unwrap().unwrap();
+        assert!(after <= before && before - after < Duration::from_secs(10));
+        assert_eq!(bitcask.get(b"foo").unwrap(), b"bar");
+    });
+}
+
+/// Expiries should survive restarts through both log and hint files, and `merge` should
+/// drop entries that have expired.
+#[test]
+fn test_ttl_persistence_and_merge() {
+    let dir = tempdir().unwrap();
+    let cfg = Arc::new(StoreConfig {
+        log_dir: dir.path().to_path_buf(),
+        max_log_file_size: 1000,
+        ..Default::default()
+    });
+    run_test(Some(cfg.clone()), |bitcask| {
+        bitcask
+            .set_with_ttl(b"long", b"lived", Duration::from_secs(3600))
+            .unwrap();
+        for i in 0..50 {
+            let key = format!("short{}", i);
+            bitcask
+                .set_with_ttl(key.as_bytes(), &random_bytes(25), Duration::from_millis(50))
+                .unwrap();
+        }
+    });
+    std::thread::sleep(Duration::from_millis(100));
+    run_test(Some(cfg.clone()), |bitcask| {
+        assert!(bitcask.ttl(b"long").unwrap().unwrap() > Duration::from_secs(3500));
+        assert!(matches!(bitcask.get(b"short0"), Err(Error::KeyMiss)));
+        bitcask.merge().unwrap();
+        assert_eq!(bitcask.len().unwrap(), 1);
+    });
+
+    let merged_bytes: u64 = std::fs::read_dir(dir.path())
+        .unwrap()
+        .flatten()
+        .filter(|f| f.path().extension() == Some(OsStr::new("cask")))
+        .map(|f| f.metadata().unwrap().len())
+        .sum();
+    assert!(merged_bytes < 100);
+
+    run_test(Some(cfg), |bitcask| {
+        assert!(bitcask.ttl(b"long").unwrap().unwrap() > Duration::from_secs(3500));
+        assert_eq!(bitcask.get(b"long").unwrap(), b"lived");
+        assert!(matches!(bitcask.get(b"short49"), Err(Error::KeyMiss)));
+    });
+}
+
+/// Every `SyncMode` should accept writes, and an explicit `sync` should always work.
+#[test]
+fn test_sync_modes() {
+    for sync_mode in [SyncMode::Always, SyncMode::Interval, SyncMode::Os] {
+        let dir = tempdir().unwrap();
+        let cfg = Arc::new(StoreConfig {
+            log_dir: dir.path().to_path_buf(),
+            max_log_file_size: 1000,
+            sync_mode,
+            sync_interval_ms: 10,
+            ..Default::default()
+        });
+        run_test(Some(cfg.clone()), |bitcask| {
+            bitcask.sync().unwrap();
+            for i in 0..30 {
+                bitcask.set(b"foo", format!("bar{}", i).as_bytes()).unwrap();
+            }
+            std::thread::sleep(Duration::from_millis(20));
+            bitcask.sync().unwrap();
+        });
+        run_test(Some(cfg), |bitcask| {
+            assert_eq!(bitcask.get(b"foo").unwrap(), b"bar29");
+        });
+    }
+}
+
+/// An explicit `sync` under `SyncMode::Os` should cover the files rotated out since the last
+/// one, and not only the current file.
+#[test]
+fn test_sync_after_rotation() {
+    let dir = tempdir().unwrap();
+    let cfg = Arc::new(StoreConfig {
+        log_dir: dir.path().to_path_buf(),
+        max_log_file_size: 1000,
+        sync_mode: SyncMode::Os,
+        ..Default::default()
+    });
+    run_test(Some(cfg.clone()), |bitcask| {
+        for i in 0..100 {
+            bitcask.set(format!("key{}", i).as_bytes(), b"bar").unwrap();
+        }
+        assert!(count_cask_files(dir.path()) > 2);
+        bitcask.sync().unwrap();
+        // Nothing's left outstanding, so a second one is a no-op.
+        bitcask.sync().unwrap();
+        bitcask.merge().unwrap();
+        bitcask.set(b"key0", b"baz").unwrap();
+        bitcask.sync().unwrap();
+    });
+    run_test(Some(cfg), |bitcask| {
+        assert_eq!(bitcask.get(b"key0").unwrap(), b"baz");
+        assert_eq!(bitcask.get(b"key99").unwrap(), b"bar");
+    });
+}
+
+/// Write three one-byte entries `a`, `b` and `c` into a fresh store, then corrupt the value of
+/// `b`, returning the directory and its only `.cask` file.
+#[cfg(unix)]
+fn store_with_corrupt_entry() -> (TempDir, std::path::PathBuf) {
+    let dir = tempdir().unwrap();
+    run_test(
+        Some(Arc::new(StoreConfig {
+            log_dir: dir.path().to_path_buf(),
+            ..Default::default()
+        })),
+        |bitcask| {
+            for key in [b"a", b"b", b"c"] {
+                bitcask.set(key, key).unwrap();
+            }
+        },
+    );
+    let cask_file = only_cask_file(dir.path());
+    let file = std::fs::OpenOptions::new()
+        .write(true)
+        .open(&cask_file)
+        .unwrap();
+    // After the 8 byte file header, each entry is 16 bytes of header, then the key and value.
+    file.write_all_at(b"!", 8 + 18 + 16 + 1).unwrap();
+    (dir, cask_file)
+}
+
+/// Reading a corrupt entry should report a CRC mismatch.
+#[cfg(unix)]
+#[test]
+fn test_crc_mismatch_on_get() {
+    run_test(None, |bitcask| {
+        bitcask.set(b"foo", b"bar").unwrap();
+        bitcask.set(b"baz", b"quux").unwrap();
+        let cask_file = only_cask_file(&bitcask.config.log_dir);
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&cask_file)
+            .unwrap();
+        file.write_all_at(b"!", 8 + 16 + 3).unwrap();
+
+        match bitcask.get(b"foo") {
+            Err(Error::CrcMismatch { path, offset }) => {
+                assert_eq!(path, cask_file);
+                assert_eq!(offset, 8);
+            }
+            _ => panic!("expected a CRC mismatch"),
+        }
+        assert_eq!(bitcask.get(b"baz").unwrap(), b"quux");
+    });
+}
+
+/// `get_bytes` should point into the mmap of closed files, copy out of the file being written
+/// to, and check CRCs either way.
+#[cfg(unix)]
+#[test]
+fn test_get_bytes() {
+    let dir = tempdir().unwrap();
+    let cfg = Arc::new(StoreConfig {
+        log_dir: dir.path().to_path_buf(),
+        ..Default::default()
+    });
+    run_test(Some(cfg.clone()), |bitcask| {
+        bitcask.set(b"mapped", b"value").unwrap();
+        bitcask.set(b"corrupt", b"value").unwrap();
+    });
+    let cask_file = only_cask_file(dir.path());
+
+    run_test(Some(cfg), |bitcask| {
+        bitcask.set(b"active", b"value").unwrap();
+        let mapped = bitcask.get_bytes(b"mapped").unwrap();
+        assert_eq!(mapped, &b"value"[..]);
+        // Both reads point at the same bytes in the mmap, rather than at copies of them.
+        assert_eq!(
+            bitcask.get_bytes(b"mapped").unwrap().as_ptr(),
+            mapped.as_ptr()
+        );
+        assert_eq!(bitcask.get_bytes(b"active").unwrap(), &b"value"[..]);
+        assert!(matches!(bitcask.get_bytes(b"missing"), Err(Error::KeyMiss)));
+
+        // The first entry takes up 16 + 6 + 5 bytes, and the second's value starts 16 + 7
+        // bytes into it.
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&cask_file)
+            .unwrap();
+        file.write_all_at(b"!", 8 + 27 + 16 + 7).unwrap();
+        assert!(matches!(
+            bitcask.get_bytes(b"corrupt"),
+            Err(Error::CrcMismatch { offset: 35, .. })
+        ));
+    });
+}
+
+/// Each `CorruptionPolicy` should handle a corrupt entry found on startup its own way.
+#[cfg(unix)]
+#[test]
+fn test_corruption_policy_on_init() {
+    let config = |dir: &TempDir, corruption_policy| {
+        Arc::new(StoreConfig {
+            log_dir: dir.path().to_path_buf(),
+            corruption_policy,
+            ..Default::default()
+        })
+    };
+
+    let (dir, _) = store_with_corrupt_entry();
+    assert!(matches!(
+        BitCask::new(config(&dir, CorruptionPolicy::Fail)),
+        Err(Error::CrcMismatch { offset: 26, .. })
+    ));
+
+    let (dir, _) = store_with_corrupt_entry();
+    run_test(Some(config(&dir, CorruptionPolicy::Skip)), |bitcask| {
+        assert_eq!(bitcask.get(b"a").unwrap(), b"a");
+        assert!(matches!(bitcask.get(b"b"), Err(Error::KeyMiss)));
+        assert_eq!(bitcask.get(b"c").unwrap(), b"c");
+    });
+
+    let (dir, cask_file) = store_with_corrupt_entry();
+    run_test(
+        Some(config(&dir, CorruptionPolicy::Quarantine)),
+        |bitcask| {
+            assert!(bitcask.is_empty().unwrap());
+        },
+    );
+    assert!(!cask_file.exists());
+    assert!(dir
+        .path()
+        .join("quarantine")
+        .join(cask_file.file_name().unwrap())
+        .exists());
+}
+
+/// A hint file with sizes or positions running past the end of the files shouldn't be trusted,
+/// whether with an allocation or with the `KeyDir`, but passed over for its log file.
+#[test]
+fn test_corrupt_hint_sizes() {
+    let varint = |mut n: u64| {
+        let mut encoded = Vec::new();
+        while n >= 0x80 {
+            encoded.push(n as u8 | 0x80);
+            n >>= 7;
+        }
+        encoded.push(n as u8);
+        encoded
+    };
+    for (key_sz, val_pos) in [(1 << 60, 8), (3, 1 << 40)] {
+        let dir = tempdir().unwrap();
+        let config = |corruption_policy| {
+            Arc::new(StoreConfig {
+                log_dir: dir.path().to_path_buf(),
+                corruption_policy,
+                ..Default::default()
+            })
+        };
+        run_test(Some(config(CorruptionPolicy::Fail)), |bitcask| {
+            bitcask.set(b"foo", b"bar").unwrap();
+        });
+        let cask_file = only_cask_file(dir.path());
+        let mut hint = std::fs::read(&cask_file).unwrap()[..8].to_vec();
+        hint.extend(1u64.to_le_bytes());
+        hint.extend([flags::EXPLICIT_TOMBSTONES, 0]);
+        hint.extend(varint(key_sz));
+        hint.extend(varint(3));
+        hint.extend(varint(val_pos));
+        hint.extend(b"foo");
+        std::fs::write(cask_file.with_extension("hint"), hint).unwrap();
+
+        for policy in [CorruptionPolicy::Fail, CorruptionPolicy::Skip] {
+            run_test(Some(config(policy)), |bitcask| {
+                assert_eq!(bitcask.get(b"foo").unwrap(), b"bar");
+            });
+        }
+    }
+}
+
+/// A hint file cut short, as by a merge interrupted while writing it, shouldn't keep the store
+/// from opening even under `CorruptionPolicy::Fail`, as its log file has everything.
+#[test]
+fn test_truncated_hint_falls_back_on_log() {
+    let dir = tempdir().unwrap();
+    let cfg = Arc::new(StoreConfig {
+        log_dir: dir.path().to_path_buf(),
+        corruption_policy: CorruptionPolicy::Fail,
+        ..Default::default()
+    });
+    run_test(Some(cfg.clone()), |bitcask| {
+        for i in 0..10 {
+            bitcask.set(format!("key{}", i).as_bytes(), b"val").unwrap();
+        }
+    });
+    run_test(Some(cfg.clone()), |bitcask| bitcask.merge().unwrap());
+    let hint_file = std::fs::read_dir(dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension() == Some(OsStr::new("hint")))
+        .unwrap();
+    let len = std::fs::metadata(&hint_file).unwrap().len();
+    let file = std::fs::OpenOptions::new()
+        .write(true)
+        .open(&hint_file)
+        .unwrap();
+    file.set_len(len - 2).unwrap();
+
+    run_test(Some(cfg), |bitcask| {
+        assert_eq!(bitcask.len().unwrap(), 10);
+        for i in 0..10 {
+            assert_eq!(bitcask.get(format!("key{}", i).as_bytes()).unwrap(), b"val");
+        }
+    });
+}
+
+/// Merging past a corrupt entry should follow the `CorruptionPolicy` as well.
+#[cfg(unix)]
+#[test]
+fn test_corruption_policy_on_merge() {
+    let (dir, _) = store_with_corrupt_entry();
+    let cfg = Arc::new(StoreConfig {
+        log_dir: dir.path().to_path_buf(),
+        corruption_policy: CorruptionPolicy::Skip,
+        ..Default::default()
+    });
+    run_test(Some(cfg.clone()), |bitcask| {
+        bitcask.merge().unwrap();
+        assert_eq!(bitcask.len().unwrap(), 2);
+    });
+    run_test(Some(cfg), |bitcask| {
+        assert_eq!(bitcask.get(b"a").unwrap(), b"a");
+        assert_eq!(bitcask.get(b"c").unwrap(), b"c");
+    });
+}
+
+/// Only one `BitCask` at a time should be able to open a log directory.
+#[test]
+fn test_directory_lock() {
+    let dir = tempdir().unwrap();
+    let cfg = Arc::new(StoreConfig {
+        log_dir: dir.path().to_path_buf(),
+        ..Default::default()
+    });
+    run_test(Some(cfg.clone()), |bitcask| {
+        bitcask.set(b"foo", b"bar").unwrap();
+        match BitCask::new(cfg.clone()) {
+            Err(e @ Error::Locked { .. }) => {
+                assert!(
+                    matches!(e, Error::Locked { pid: Some(pid), .. } if pid == std::process::id())
+                );
+                assert!(e.to_string().contains(&std::process::id().to_string()));
+            }
+            _ => panic!("expected the directory to be locked"),
+        }
+    });
+    // Released on drop.
+    run_test(Some(cfg), |bitcask| {
+        assert_eq!(bitcask.get(b"foo").unwrap(), b"bar");
+    });
+}
+
+/// A read-only store should serve reads next to a writer, and see its writes on refresh.
+#[cfg(unix)]
+#[test]
+fn test_read_only() {
+    let dir = tempdir().unwrap();
+    let cfg = Arc::new(StoreConfig {
+        log_dir: dir.path().to_path_buf(),
+        max_log_file_size: 1000,
+        ..Default::default()
+    });
+    run_test(Some(cfg.clone()), |writer| {
+        for i in 0..50u32 {
+            writer.set(&i.to_ne_bytes(), &random_bytes(20)).unwrap();
+        }
+        // Doesn't need the lock the writer is holding.
+        let reader = BitCask::open_read_only(cfg.clone()).unwrap();
+        assert_eq!(reader.len().unwrap(), 50);
+        for i in 0..50u32 {
+            let key = i.to_ne_bytes();
+            assert_eq!(reader.get(&key).unwrap(), writer.get(&key).unwrap());
+        }
+        assert!(matches!(reader.set(b"foo", b"bar"), Err(Error::ReadOnly)));
+        assert!(matches!(reader.delete(b"foo"), Err(Error::ReadOnly)));
+        assert!(matches!(reader.merge(), Err(Error::ReadOnly)));
+
+        writer.set(b"foo", b"bar").unwrap();
+        writer.delete(&0u32.to_ne_bytes()).unwrap();
+        writer.merge().unwrap();
+        assert!(matches!(reader.get(b"foo"), Err(Error::KeyMiss)));
+        reader.refresh().unwrap();
+        assert_eq!(reader.get(b"foo").unwrap(), b"bar");
+        assert!(matches!(
+            reader.get(&0u32.to_ne_bytes()),
+            Err(Error::KeyMiss)
+        ));
+        assert_eq!(reader.keys().count(), 50);
+    });
+
+    // An incomplete write at the end is skipped rather than truncated.
+    let dir = tempdir().unwrap();
+    let cfg = Arc::new(StoreConfig {
+        log_dir: dir.path().to_path_buf(),
+        ..Default::default()
+    });
+    run_test(Some(cfg.clone()), |writer| {
+        writer.set(b"foo", b"bar").unwrap();
+    });
+    let path = only_cask_file(dir.path());
+    let file = std::fs::OpenOptions::new()
+        .append(true)
+        .open(&path)
+        .unwrap();
+    file.write_all_at(&[0xff; 10], file.metadata().unwrap().len())
+        .unwrap();
+    let len = file.metadata().unwrap().len();
+    let reader = BitCask::open_read_only(cfg).unwrap();
+    assert_eq!(reader.get(b"foo").unwrap(), b"bar");
+    assert_eq!(reader.len().unwrap(), 1);
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+}
+
+/// Deletes are marked by a flag, so any value can be stored, while files from before the
+/// flag existed still read their deletes correctly.
+#[test]
+fn test_tombstones() {
+    let legacy_tombstone = "☗".as_bytes();
+    let dir = tempdir().unwrap();
+    let cfg = Arc::new(StoreConfig {
+        log_dir: dir.path().to_path_buf(),
+        ..Default::default()
+    });
+    run_test(Some(cfg.clone()), |bitcask| {
+        bitcask.set(b"foo", legacy_tombstone).unwrap();
+        bitcask.set(b"bar", b"baz").unwrap();
+        bitcask.delete(b"bar").unwrap();
+        assert_eq!(bitcask.get(b"foo").unwrap(), legacy_tombstone);
+        assert_eq!(bitcask.len().unwrap(), 1);
+    });
+    run_test(Some(cfg.clone()), |bitcask| {
+        assert_eq!(bitcask.get(b"foo").unwrap(), legacy_tombstone);
+        assert!(matches!(bitcask.get(b"bar"), Err(Error::KeyMiss)));
+        bitcask.merge().unwrap();
+    });
+    run_test(Some(cfg), |bitcask| {
+        assert_eq!(bitcask.get(b"foo").unwrap(), legacy_tombstone);
+        assert!(matches!(bitcask.get(b"bar"), Err(Error::KeyMiss)));
+        assert_eq!(bitcask.keys().count(), 1);
+    });
+
+    // A file written before deletes were flagged, deleting "foo" by value.
+    let dir = tempdir().unwrap();
+    let cfg = Arc::new(StoreConfig {
+        log_dir: dir.path().to_path_buf(),
+        ..Default::default()
+    });
+    let legacy: Vec<u8> = [
+        (b"foo", b"bar".as_slice()),
+        (b"baz", b"quux"),
+        (b"foo", legacy_tombstone),
+    ]
+    .into_iter()
+    .enumerate()
+    .flat_map(|(ts, (key, val))| {
+        LogEntry {
+            key: key.to_vec(),
+            val: val.to_vec(),
+            ts: ts as u128 + 1,
+            flags: 0,
+            expiry: None,
+        }
+        .serialize_with_crc_as(Format::Legacy)
+    })
+    .collect();
+    std::fs::write(dir.path().join("1.cask"), legacy).unwrap();
+    run_test(Some(cfg.clone()), |bitcask| {
+        assert!(matches!(bitcask.get(b"foo"), Err(Error::KeyMiss)));
+        assert_eq!(bitcask.get(b"baz").unwrap(), b"quux");
+        bitcask.merge().unwrap();
+    });
+    run_test(Some(cfg), |bitcask| {
+        assert!(matches!(bitcask.get(b"foo"), Err(Error::KeyMiss)));
+        assert_eq!(bitcask.get(b"baz").unwrap(), b"quux");
+        assert_eq!(bitcask.len().unwrap(), 1);
+    });
+}
+
+/// Files in older formats should still read correctly, and get rewritten in the current
+/// format once the store is opened for writing.
+#[test]
+fn test_format_upgrade() {
+    let dir = tempdir().unwrap();
+    let cfg = Arc::new(StoreConfig {
+        log_dir: dir.path().to_path_buf(),
+        ..Default::default()
+    });
+    let old_entry = |key: &[u8], val: &[u8], ts: u128| LogEntry {
+        key: key.to_vec(),
+        val: val.to_vec(),
+        ts,
+        flags: 0,
+        expiry: None,
+    };
+    let write_old = |name: &str, format: Format, entries: &[LogEntry], with_hint: bool| {
+        let path = dir.path().join(name);
+        let mut cask = Vec::new();
+        if format != Format::Legacy {
+            cask.extend(MAGIC);
+            cask.extend(format.version().to_le_bytes());
+            cask.extend(0u16.to_le_bytes());
+        }
+        let mut hint = cask.clone();
+        for entry in entries {
+            let item = Item {
+                path: path.clone(),
+                key_sz: entry.key.len(),
+                val_sz: entry.val.len(),
+                val_pos: cask.len() as u64,
+                ts: entry.ts,
+                expiry: None,
+            };
+            hint.extend(item.serialize_as_hint_in(format, &entry.key, entry.flags));
+            cask.extend(entry.serialize_with_crc_as(format));
+        }
+        std::fs::write(&path, cask).unwrap();
+        if with_hint {
+            std::fs::write(path.with_extension("hint"), hint).unwrap();
+        }
+    };
+    // Headerless, with deletes marked by value.
+    write_old(
+        "1.cask",
+        Format::Legacy,
+        &[old_entry(b"foo", b"bar", 1), old_entry(b"baz", b"quux", 2)],
+        false,
+    );
+    // As left behind by a merge, deleting "foo" in a way only the entry itself shows.
+    write_old(
+        "2.cask",
+        Format::Legacy,
+        &[
+            old_entry(b"foo", "☗".as_bytes(), 3),
+            old_entry(b"qux", b"x", 4),
+        ],
+        true,
+    );
+    let mut v1_entry = old_entry(b"v1", b"yes", 5);
+    v1_entry.flags = flags::EXPLICIT_TOMBSTONES;
+    write_old("3.cask", Format::V1, &[v1_entry], true);
+
+    let version = |name: &str| {
+        let bytes = std::fs::read(dir.path().join(name)).unwrap();
+        (bytes[..4] == MAGIC).then(|| u16::from_le_bytes([bytes[4], bytes[5]]))
+    };
+    let check = |bitcask: &BitCask| {
+        assert!(matches!(bitcask.get(b"foo"), Err(Error::KeyMiss)));
+        assert_eq!(bitcask.get(b"baz").unwrap(), b"quux");
+        assert_eq!(bitcask.get(b"qux").unwrap(), b"x");
+        assert_eq!(bitcask.get(b"v1").unwrap(), b"yes");
+        assert_eq!(bitcask.len().unwrap(), 3);
+    };
+
+    check(&BitCask::open_read_only(cfg.clone()).unwrap());
+    assert_eq!(version("1.cask"), None);
+    assert_eq!(version("3.cask"), Some(1));
+
+    // Left over from upgrades cut short, one halfway through writing its new log file, and
+    // one after renaming it into place but before its new hint file.
+    std::fs::write(dir.path().join("2.cask.upgrade"), &MAGIC[..2]).unwrap();
+    std::fs::write(dir.path().join("5.hint.upgrade"), MAGIC).unwrap();
+
+    run_test(Some(cfg.clone()), |bitcask| check(bitcask));
+    for name in ["1.cask", "2.cask", "2.hint", "3.cask", "3.hint"] {
+        assert_eq!(version(name), Some(Format::CURRENT.version()), "{}", name);
+    }
+    assert!(!dir.path().join("1.hint").exists());
+    assert!(std::fs::read_dir(dir.path())
+        .unwrap()
+        .flatten()
+        .all(|f| f.path().extension() != Some(OsStr::new("upgrade"))));
+    run_test(Some(cfg.clone()), |bitcask| {
+        check(bitcask);
+        bitcask.merge().unwrap();
+        check(bitcask);
+    });
+
+    // Versions from the future are refused rather than misread.
+    let mut future = MAGIC.to_vec();
+    future.extend(99u16.to_le_bytes());
+    future.extend(0u16.to_le_bytes());
+    std::fs::write(dir.path().join("4.cask"), future).unwrap();
+    assert!(matches!(
+        BitCask::new(cfg),
+        Err(Error::UnsupportedFormat { version: 99, .. })
+    ));
+}
+
+/// Reading from the file being written to shouldn't throw off where the next write lands.
+#[test]
+fn test_read_between_writes() {
+    run_test(None, |bitcask| {
+        bitcask.set(b"a", b"1").unwrap();
+        bitcask.set(b"b", b"2").unwrap();
+        assert_eq!(bitcask.get(b"a").unwrap(), b"1");
+        bitcask.set(b"c", b"3").unwrap();
+        assert_eq!(bitcask.get(b"c").unwrap(), b"3");
+        assert_eq!(bitcask.get(b"b").unwrap(), b"2");
+    });
+}
+
+/// Values should be compressed on disk when configured to, and `merge` should bring old entries
+/// in line with changed settings.
+#[cfg(all(feature = "lz4", feature = "zstd"))]
+#[test]
+fn test_compression() {
+    let dir = tempdir().unwrap();
+    let cfg = |compression| {
+        Arc::new(StoreConfig {
+            log_dir: dir.path().to_path_buf(),
+            compression,
+            ..Default::default()
+        })
+    };
+    let big = r#"{"name": "bitcask", "tags": ["log", "store"]}"#.repeat(100);
+    let log_size = || -> u64 {
+        std::fs::read_dir(dir.path())
+            .unwrap()
+            .flatten()
+            .filter(|f| f.path().extension() == Some(OsStr::new("cask")))
+            .map(|f| f.metadata().unwrap().len())
+            .sum()
+    };
+
+    run_test(Some(cfg(Compression::Lz4)), |bitcask| {
+        bitcask.set(b"big", big.as_bytes()).unwrap();
+        bitcask.set(b"small", b"tiny").unwrap();
+        let mut batch = WriteBatch::new();
+        batch.set(b"batched", big.as_bytes());
+        bitcask.write_batch(batch).unwrap();
+        assert_eq!(bitcask.get(b"big").unwrap(), big.as_bytes());
+        assert_eq!(bitcask.get(b"small").unwrap(), b"tiny");
+        assert_eq!(bitcask.get(b"batched").unwrap(), big.as_bytes());
+    });
+    assert!(log_size() < big.len() as u64);
+
+    for compression in [Compression::Zstd, Compression::None, Compression::Lz4] {
+        run_test(Some(cfg(compression)), |bitcask| {
+            bitcask.merge().unwrap();
+            assert_eq!(bitcask.get(b"big").unwrap(), big.as_bytes());
+            assert_eq!(bitcask.get(b"small").unwrap(), b"tiny");
+            assert_eq!(bitcask.get(b"batched").unwrap(), big.as_bytes());
+        });
+        // Uncompressed, both big values are there in full.
+        assert_eq!(
+            log_size() > 2 * big.len() as u64,
+            compression == Compression::None
+        );
+    }
+}
+
+/// Asking for compression the store wasn't built with should fail up front.
+#[cfg(not(feature = "zstd"))]
+#[test]
+fn test_compression_unavailable() {
+    let dir = tempdir().unwrap();
+    let cfg = Arc::new(StoreConfig {
+        log_dir: dir.path().to_path_buf(),
+        compression: Compression::Zstd,
+        ..Default::default()
+    });
+    assert!(matches!(BitCask::new(cfg), Err(Error::InvalidConfig(_))));
+}
+
+/// With a key configured, neither keys nor values show up in the log or hint files, and
+/// `merge` moves everything written under a retired key over to the current one.
+#[test]
+fn test_encryption() {
+    let dir = tempdir().unwrap();
+    let (old_key, new_key) = ("11".repeat(32), "22".repeat(32));
+    let cfg = |key: &str, retired: &[&String]| {
+        Arc::new(StoreConfig {
+            log_dir: dir.path().to_path_buf(),
+            encryption_key: Some(key.to_string()),
+            retired_encryption_keys: retired.iter().map(|key| key.to_string()).collect(),
+            ..Default::default()
+        })
+    };
+    let assert_no_plaintext = || {
+        for file in std::fs::read_dir(dir.path()).unwrap().flatten() {
+            let contents = std::fs::read(file.path()).unwrap();
+            for needle in [&b"secret-key"[..], b"secret-value", b"batched-value"] {
+                assert!(!contents.windows(needle.len()).any(|w| w == needle));
+            }
+        }
+    };
+
+    run_test(Some(cfg(&old_key, &[])), |bitcask| {
+        bitcask.set(b"secret-key", b"secret-value").unwrap();
+        bitcask.set(b"gone", b"soon").unwrap();
+        bitcask.delete(b"gone").unwrap();
+        let mut batch = WriteBatch::new();
+        batch.set(b"batched", b"batched-value");
+        bitcask.write_batch(batch).unwrap();
+    });
+    assert_no_plaintext();
+
+    run_test(Some(cfg(&new_key, &[&old_key])), |bitcask| {
+        assert_eq!(bitcask.get(b"secret-key").unwrap(), b"secret-value");
+        assert!(matches!(bitcask.get(b"gone"), Err(Error::KeyMiss)));
+        bitcask.merge().unwrap();
+    });
+    assert_no_plaintext();
+
+    // Everything is under the new key now, with hints to load it from.
+    let key_dir = tempdir().unwrap();
+    let key_file = key_dir.path().join("key");
+    std::fs::write(&key_file, format!("{}\n", new_key)).unwrap();
+    let from_file = Arc::new(StoreConfig {
+        log_dir: dir.path().to_path_buf(),
+        encryption_key_file: Some(key_file),
+        ..Default::default()
+    });
+    run_test(Some(from_file), |bitcask| {
+        assert_eq!(bitcask.get(b"secret-key").unwrap(), b"secret-value");
+        assert_eq!(bitcask.get(b"batched").unwrap(), b"batched-value");
+        assert_eq!(bitcask.len().unwrap(), 2);
+    });
+    assert!(matches!(
+        BitCask::new(cfg(&old_key, &[])),
+        Err(Error::Decryption { .. })
+    ));
+    assert!(matches!(
+        BitCask::new(cfg("not hex", &[])),
+        Err(Error::InvalidConfig(_))
+    ));
+}
+
+/// Encrypted values are bound to their key and header, so swapping the values of two entries
+/// written in one batch, with the same timestamp, gets caught even with the CRCs fixed up.
+#[test]
+fn test_encrypted_values_bound_to_their_entries() {
+    let dir = tempdir().unwrap();
+    let cfg = Arc::new(StoreConfig {
+        log_dir: dir.path().to_path_buf(),
+        encryption_key: Some("11".repeat(32)),
+        ..Default::default()
+    });
+    run_test(Some(cfg.clone()), |bitcask| {
+        let mut batch = WriteBatch::new();
+        batch.set(b"a", b"1");
+        batch.set(b"b", b"2");
+        bitcask.write_batch(batch).unwrap();
+    });
+
+    // Both entries have a one byte key and value, so their sealed fields line up.
+    let cask_file = only_cask_file(dir.path());
+    let mut cask = std::fs::read(&cask_file).unwrap();
+    let sealed_sz = 1 + 24 + 16;
+    let header_sz = 4 + 8 + 1 + 1 + 1 + 1;
+    let entry_sz = header_sz + 2 * sealed_sz;
+    let parse = |cask: &[u8], start: usize| LogEntry {
+        key: cask[start + header_sz..][..sealed_sz].to_vec(),
+        val: cask[start + header_sz + sealed_sz..][..sealed_sz].to_vec(),
+        ts: u64::from_le_bytes(cask[start + 4..][..8].try_into().unwrap()) as u128,
+        flags: cask[start + 12],
+        expiry: None,
+    };
+    let (mut a, mut b) = (parse(&cask, 8), parse(&cask, 8 + entry_sz));
+    assert_eq!(a.ts, b.ts);
+    std::mem::swap(&mut a.val, &mut b.val);
+    for (start, entry) in [(8, a), (8 + entry_sz, b)] {
+        cask[start..][..4].copy_from_slice(&entry.crc_as(Format::V2).to_le_bytes());
+        cask[start + 4..][..entry_sz - 4].copy_from_slice(&entry.serialize_as(Format::V2));
+    }
+    std::fs::write(&cask_file, cask).unwrap();
+
+    run_test(Some(cfg), |bitcask| {
+        assert!(matches!(bitcask.get(b"a"), Err(Error::Decryption { .. })));
+        assert!(matches!(bitcask.get(b"b"), Err(Error::Decryption { .. })));
+    });
+}
+
+/// A value bigger than `max_log_file_size` gets a log file of its own, and stays readable
+/// across reopening and merging.
+#[test]
+fn test_value_larger_than_log_file() {
+    let dir = tempdir().unwrap();
+    let cfg = Arc::new(StoreConfig {
+        log_dir: dir.path().to_path_buf(),
+        max_log_file_size: 1000,
+        ..Default::default()
+    });
+    let big = vec![7u8; 5000];
+    let cask_sizes = || -> Vec<u64> {
+        let mut sizes: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .flatten()
+            .filter(|f| f.path().extension() == Some(OsStr::new("cask")))
+            .map(|f| f.metadata().unwrap().len())
+            .collect();
+        sizes.sort();
+        sizes
+    };
+
+    run_test(Some(cfg.clone()), |bitcask| {
+        bitcask.set(b"before", b"small").unwrap();
+        bitcask.set(b"big", &big).unwrap();
+        bitcask.set(b"after", b"small").unwrap();
+        assert_eq!(bitcask.get(b"big").unwrap(), big);
+        assert_eq!(bitcask.get(b"after").unwrap(), b"small");
+    });
+    let sizes = cask_sizes();
+    assert_eq!(sizes.len(), 3);
+    assert!(sizes[..2].iter().all(|&size| size < 1000));
+    assert!(sizes[2] > 5000 && sizes[2] < 5100);
+
+    run_test(Some(cfg), |bitcask| {
+        assert_eq!(bitcask.get(b"big").unwrap(), big);
+        bitcask.merge().unwrap();
+        assert_eq!(bitcask.get(b"big").unwrap(), big);
+        assert_eq!(bitcask.get(b"before").unwrap(), b"small");
+        assert_eq!(bitcask.get(b"after").unwrap(), b"small");
+    });
+}
+
+/// Values over `max_value_size` get rejected without anything being written.
+#[test]
+fn test_max_value_size() {
+    let dir = tempdir().unwrap();
+    let cfg = Arc::new(StoreConfig {
+        log_dir: dir.path().to_path_buf(),
+        max_value_size: 100,
+        ..Default::default()
+    });
+    run_test(Some(cfg), |bitcask| {
+        bitcask.set(b"fits", &[0u8; 100]).unwrap();
+        assert!(matches!(
+            bitcask.set(b"too-big", &[0u8; 101]),
+            Err(Error::ValueTooLarge {
+                size: 101,
+                max: 100
+            })
+        ));
+        let mut batch = WriteBatch::new();
+        batch.set(b"batched", b"small").set(b"too-big", &[0u8; 101]);
+        assert!(matches!(
+            bitcask.write_batch(batch),
+            Err(Error::ValueTooLarge { .. })
+        ));
+        assert!(matches!(bitcask.get(b"too-big"), Err(Error::KeyMiss)));
+        assert!(matches!(bitcask.get(b"batched"), Err(Error::KeyMiss)));
+        assert_eq!(bitcask.len().unwrap(), 1);
+    });
+}